@@ -0,0 +1,336 @@
+use mcproto_rs::uuid::UUID4;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use hyper::{Body, Client, Request, StatusCode, client::HttpConnector};
+use hyper_tls::HttpsConnector;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
+
+pub const DEFAULT_SESSION_URL: &str = "https://sessionserver.mojang.com";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GameProfile {
+    pub id: UUID4,
+    pub name: String,
+    pub properties: Vec<ProfileProperty>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProfileProperty {
+    pub name: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl GameProfile {
+    pub fn new(id: UUID4, name: impl Into<String>) -> Self {
+        Self {
+            id,
+            name: name.into(),
+            properties: Vec::new(),
+        }
+    }
+
+    pub fn with_property(mut self, property: ProfileProperty) -> Self {
+        self.properties.push(property);
+        self
+    }
+
+    pub fn property(&self, name: &str) -> Option<&ProfileProperty> {
+        self.properties.iter().find(|p| p.name == name)
+    }
+}
+
+// the session server sends ids as undashed hex, which is why we don't (de)serialize UUID4 directly
+#[derive(Serialize, Deserialize)]
+struct ProfileJson {
+    id: String,
+    name: String,
+    #[serde(default)]
+    properties: Vec<ProfileProperty>,
+}
+
+impl ProfileJson {
+    fn into_profile(self) -> Result<GameProfile> {
+        Ok(GameProfile {
+            id: parse_uuid(&self.id)?,
+            name: self.name,
+            properties: self.properties,
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JoinJson<'a> {
+    access_token: &'a str,
+    selected_profile: String,
+    server_id: &'a str,
+}
+
+/// The two calls made against the session server during an online-mode login. Clients call
+/// `join` before answering an EncryptionRequest, and servers call `has_joined` after they have
+/// received the EncryptionResponse.
+#[async_trait]
+pub trait SessionService: Send + Sync {
+    /// Returns the authenticated profile, or `None` if the session server does not know about
+    /// this login.
+    async fn has_joined(&self, username: &str, server_hash: &str, ip: Option<IpAddr>) -> Result<Option<GameProfile>>;
+
+    async fn join(&self, access_token: &str, profile: UUID4, server_hash: &str) -> Result<()>;
+}
+
+pub struct HttpSessionService {
+    base_url: String,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl HttpSessionService {
+    pub fn new() -> Self {
+        Self::with_base_url(DEFAULT_SESSION_URL)
+    }
+
+    pub fn with_base_url(base_url: impl Into<String>) -> Self {
+        let mut base_url = base_url.into();
+        while base_url.ends_with('/') {
+            base_url.pop();
+        }
+
+        Self {
+            base_url,
+            client: Client::builder().build(HttpsConnector::new()),
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+}
+
+impl Default for HttpSessionService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl SessionService for HttpSessionService {
+    async fn has_joined(&self, username: &str, server_hash: &str, ip: Option<IpAddr>) -> Result<Option<GameProfile>> {
+        let mut url = format!(
+            "{}/session/minecraft/hasJoined?username={}&serverId={}",
+            self.base_url,
+            url_encode(username),
+            url_encode(server_hash));
+        if let Some(ip) = ip {
+            url.push_str("&ip=");
+            url.push_str(&url_encode(&ip.to_string()));
+        }
+
+        let request = Request::get(url).body(Body::empty())?;
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        match status {
+            StatusCode::OK => Ok(Some(serde_json::from_slice::<ProfileJson>(&body)?.into_profile()?)),
+            StatusCode::NO_CONTENT => Ok(None),
+            other => Err(anyhow!("session server returned {} for hasJoined: {}", other, String::from_utf8_lossy(&body))),
+        }
+    }
+
+    async fn join(&self, access_token: &str, profile: UUID4, server_hash: &str) -> Result<()> {
+        let body = serde_json::to_vec(&JoinJson {
+            access_token,
+            selected_profile: undashed(profile),
+            server_id: server_hash,
+        })?;
+
+        let request = Request::post(format!("{}/session/minecraft/join", self.base_url))
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))?;
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Err(anyhow!("session server returned {} for join: {}", status, String::from_utf8_lossy(&body)))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum MockResponse {
+    Accept(GameProfile),
+    Reject,
+    Fail(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockJoin {
+    pub access_token: String,
+    pub profile: UUID4,
+    pub server_hash: String,
+}
+
+#[derive(Default)]
+struct MockState {
+    profiles: HashMap<UUID4, GameProfile>,
+    scripted: HashMap<String, MockResponse>,
+    rejected_tokens: Vec<String>,
+    joins: Vec<MockJoin>,
+}
+
+/// An in-process `SessionService` for tests.
+///
+/// By default it behaves like the real session server: a `join` for a registered profile is
+/// remembered, and a later `has_joined` with the same name and server hash returns that profile.
+/// Responses for a username can also be scripted directly, which skips the join bookkeeping.
+#[derive(Default)]
+pub struct MockSessionService {
+    state: Mutex<MockState>,
+}
+
+impl MockSessionService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, profile: GameProfile) {
+        self.lock().profiles.insert(profile.id, profile);
+    }
+
+    pub fn script(&self, username: impl Into<String>, response: MockResponse) {
+        self.lock().scripted.insert(username.into(), response);
+    }
+
+    pub fn accept(&self, profile: GameProfile) {
+        self.script(profile.name.clone(), MockResponse::Accept(profile));
+    }
+
+    pub fn reject(&self, username: impl Into<String>) {
+        self.script(username, MockResponse::Reject);
+    }
+
+    pub fn reject_token(&self, access_token: impl Into<String>) {
+        self.lock().rejected_tokens.push(access_token.into());
+    }
+
+    pub fn joins(&self) -> Vec<MockJoin> {
+        self.lock().joins.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<MockState> {
+        self.state.lock().expect("mock session state poisoned")
+    }
+}
+
+#[async_trait]
+impl SessionService for MockSessionService {
+    async fn has_joined(&self, username: &str, server_hash: &str, _ip: Option<IpAddr>) -> Result<Option<GameProfile>> {
+        let state = self.lock();
+        if let Some(response) = state.scripted.get(username) {
+            return match response {
+                MockResponse::Accept(profile) => Ok(Some(profile.clone())),
+                MockResponse::Reject => Ok(None),
+                MockResponse::Fail(err) => Err(anyhow!("{}", err)),
+            };
+        }
+
+        Ok(state.joins.iter()
+            .rev()
+            .filter(|join| join.server_hash == server_hash)
+            .filter_map(|join| state.profiles.get(&join.profile))
+            .find(|profile| profile.name == username)
+            .cloned())
+    }
+
+    async fn join(&self, access_token: &str, profile: UUID4, server_hash: &str) -> Result<()> {
+        let mut state = self.lock();
+        if state.rejected_tokens.iter().any(|token| token == access_token) {
+            return Err(anyhow!("invalid session (mock rejected access token)"));
+        }
+
+        if !state.profiles.contains_key(&profile) {
+            return Err(anyhow!("unknown profile {}", profile));
+        }
+
+        state.joins.push(MockJoin {
+            access_token: access_token.to_owned(),
+            profile,
+            server_hash: server_hash.to_owned(),
+        });
+        Ok(())
+    }
+}
+
+/// Computes the "server hash" used by both `join` and `has_joined`. This is the SHA-1 of the
+/// server id, shared secret and public key, printed as a signed (two's complement) hex number.
+pub fn server_hash(server_id: &str, shared_secret: &[u8], public_key: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(server_id.as_bytes());
+    hasher.update(shared_secret);
+    hasher.update(public_key);
+    let mut digest: [u8; 20] = hasher.finalize().into();
+
+    let negative = digest[0] & 0x80 != 0;
+    if negative {
+        // two's complement negation, from the least significant byte up
+        let mut carry = true;
+        for byte in digest.iter_mut().rev() {
+            *byte = !*byte;
+            if carry {
+                let (next, overflow) = byte.overflowing_add(1);
+                *byte = next;
+                carry = overflow;
+            }
+        }
+    }
+
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    let hex = hex.trim_start_matches('0');
+    let hex = if hex.is_empty() { "0" } else { hex };
+    if negative {
+        format!("-{}", hex)
+    } else {
+        hex.to_owned()
+    }
+}
+
+pub(crate) fn parse_uuid(raw: &str) -> Result<UUID4> {
+    let hex: String = raw.chars().filter(|c| *c != '-').collect();
+    if hex.len() != 32 {
+        return Err(anyhow!("invalid uuid {:?}", raw));
+    }
+
+    Ok(UUID4::from(u128::from_str_radix(&hex, 16)?))
+}
+
+pub(crate) fn undashed(id: UUID4) -> String {
+    format!("{:032x}", id.to_u128())
+}
+
+fn url_encode(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len());
+    for byte in raw.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(byte as char),
+            other => out.push_str(&format!("%{:02X}", other)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_hash_matches_java_biginteger_hex() {
+        assert_eq!(server_hash("Notch", &[], &[]), "4ed1f46bbe04bc756bcb17c0c7ce3e4632f06a48");
+        assert_eq!(server_hash("jeb_", &[], &[]), "-7c9d5b0044c130109a5d7b5fb5c317c02b4e28c1");
+        // leading zeros are dropped
+        assert_eq!(server_hash("simon", &[], &[]), "88e16a1019277b15d58faf0541e11910eb756f6");
+    }
+}
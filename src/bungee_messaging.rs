@@ -0,0 +1,416 @@
+use super::{
+    auth::{parse_uuid, undashed},
+    plugin_channels::{PluginMessage, plugin_message},
+    proxy::{Action, PacketHook, RelayHandle},
+    util::{ByteReader, ByteWriter},
+};
+use mcproto_rs::{
+    protocol::{HasPacketId, PacketDirection},
+    types::RemainingBytes,
+    uuid::UUID4,
+    v1_15_2::{Packet578, RawPacket578, PlayClientPluginMessageSpec},
+};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use std::{net::SocketAddr, sync::Arc};
+
+/// The channel backends use to talk to a BungeeCord-compatible proxy.
+pub const BUNGEE_CHANNEL: &str = "bungeecord:main";
+/// The pre-1.13 name of `BUNGEE_CHANNEL`, which some plugins still send on.
+pub const LEGACY_BUNGEE_CHANNEL: &str = "BungeeCord";
+
+/// Which servers a request applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerTarget {
+    All,
+    /// Every server with at least one player (only meaningful for `Forward`).
+    Online,
+    Server(String),
+}
+
+impl ServerTarget {
+    fn parse(raw: String) -> Self {
+        match raw.as_str() {
+            "ALL" => ServerTarget::All,
+            "ONLINE" => ServerTarget::Online,
+            _ => ServerTarget::Server(raw),
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            ServerTarget::All => "ALL",
+            ServerTarget::Online => "ONLINE",
+            ServerTarget::Server(name) => name,
+        }
+    }
+}
+
+/// A message from a backend plugin to the proxy, sent through a player's connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BungeeRequest {
+    Connect { server: String },
+    ConnectOther { player: String, server: String },
+    Ip,
+    IpOther { player: String },
+    PlayerCount { server: ServerTarget },
+    PlayerList { server: ServerTarget },
+    GetServers,
+    GetServer,
+    /// `player` is `ALL` to message everyone.
+    Message { player: String, message: String },
+    MessageRaw { player: String, json: String },
+    Forward { server: ServerTarget, channel: String, data: Vec<u8> },
+    ForwardToPlayer { player: String, channel: String, data: Vec<u8> },
+    Uuid,
+    UuidOther { player: String },
+    ServerIp { server: String },
+    KickPlayer { player: String, reason: String },
+}
+
+/// A message from the proxy to a backend, answering a `BungeeRequest` (or delivering a
+/// `Forward`).
+#[derive(Clone, Debug, PartialEq)]
+pub enum BungeeResponse {
+    Ip { ip: String, port: i32 },
+    IpOther { player: String, ip: String, port: i32 },
+    PlayerCount { server: String, count: i32 },
+    PlayerList { server: String, players: Vec<String> },
+    GetServers { servers: Vec<String> },
+    GetServer { server: String },
+    Uuid { uuid: UUID4 },
+    UuidOther { player: String, uuid: UUID4 },
+    ServerIp { server: String, ip: String, port: u16 },
+    /// Data sent with `Forward` or `ForwardToPlayer`, which arrives under the plugin's own
+    /// subchannel name.
+    Forward { channel: String, data: Vec<u8> },
+}
+
+impl PluginMessage for BungeeRequest {
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut input = ByteReader::new(data);
+        let subchannel = input.java_utf()?;
+        let request = match subchannel.as_str() {
+            "Connect" => BungeeRequest::Connect { server: input.java_utf()? },
+            "ConnectOther" => BungeeRequest::ConnectOther { player: input.java_utf()?, server: input.java_utf()? },
+            "IP" => BungeeRequest::Ip,
+            "IPOther" => BungeeRequest::IpOther { player: input.java_utf()? },
+            "PlayerCount" => BungeeRequest::PlayerCount { server: ServerTarget::parse(input.java_utf()?) },
+            "PlayerList" => BungeeRequest::PlayerList { server: ServerTarget::parse(input.java_utf()?) },
+            "GetServers" => BungeeRequest::GetServers,
+            "GetServer" => BungeeRequest::GetServer,
+            "Message" => BungeeRequest::Message { player: input.java_utf()?, message: input.java_utf()? },
+            "MessageRaw" => BungeeRequest::MessageRaw { player: input.java_utf()?, json: input.java_utf()? },
+            "Forward" => BungeeRequest::Forward {
+                server: ServerTarget::parse(input.java_utf()?),
+                channel: input.java_utf()?,
+                data: input.java_bytes()?,
+            },
+            "ForwardToPlayer" => BungeeRequest::ForwardToPlayer {
+                player: input.java_utf()?,
+                channel: input.java_utf()?,
+                data: input.java_bytes()?,
+            },
+            "UUID" => BungeeRequest::Uuid,
+            "UUIDOther" => BungeeRequest::UuidOther { player: input.java_utf()? },
+            "ServerIP" => BungeeRequest::ServerIp { server: input.java_utf()? },
+            "KickPlayer" => BungeeRequest::KickPlayer { player: input.java_utf()?, reason: input.java_utf()? },
+            other => return Err(anyhow!("unknown bungee subchannel {:?}", other)),
+        };
+        Ok(request)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = ByteWriter::default();
+        match self {
+            BungeeRequest::Connect { server } => out.java_utf("Connect")?.java_utf(server)?,
+            BungeeRequest::ConnectOther { player, server } => out.java_utf("ConnectOther")?.java_utf(player)?.java_utf(server)?,
+            BungeeRequest::Ip => out.java_utf("IP")?,
+            BungeeRequest::IpOther { player } => out.java_utf("IPOther")?.java_utf(player)?,
+            BungeeRequest::PlayerCount { server } => out.java_utf("PlayerCount")?.java_utf(server.as_str())?,
+            BungeeRequest::PlayerList { server } => out.java_utf("PlayerList")?.java_utf(server.as_str())?,
+            BungeeRequest::GetServers => out.java_utf("GetServers")?,
+            BungeeRequest::GetServer => out.java_utf("GetServer")?,
+            BungeeRequest::Message { player, message } => out.java_utf("Message")?.java_utf(player)?.java_utf(message)?,
+            BungeeRequest::MessageRaw { player, json } => out.java_utf("MessageRaw")?.java_utf(player)?.java_utf(json)?,
+            BungeeRequest::Forward { server, channel, data } => out.java_utf("Forward")?.java_utf(server.as_str())?.java_utf(channel)?.java_bytes(data)?,
+            BungeeRequest::ForwardToPlayer { player, channel, data } => out.java_utf("ForwardToPlayer")?.java_utf(player)?.java_utf(channel)?.java_bytes(data)?,
+            BungeeRequest::Uuid => out.java_utf("UUID")?,
+            BungeeRequest::UuidOther { player } => out.java_utf("UUIDOther")?.java_utf(player)?,
+            BungeeRequest::ServerIp { server } => out.java_utf("ServerIP")?.java_utf(server)?,
+            BungeeRequest::KickPlayer { player, reason } => out.java_utf("KickPlayer")?.java_utf(player)?.java_utf(reason)?,
+        };
+        Ok(out.into_inner())
+    }
+}
+
+impl PluginMessage for BungeeResponse {
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut input = ByteReader::new(data);
+        let subchannel = input.java_utf()?;
+        let response = match subchannel.as_str() {
+            "IP" => BungeeResponse::Ip { ip: input.java_utf()?, port: input.read::<i32>()? },
+            "IPOther" => BungeeResponse::IpOther { player: input.java_utf()?, ip: input.java_utf()?, port: input.read::<i32>()? },
+            "PlayerCount" => BungeeResponse::PlayerCount { server: input.java_utf()?, count: input.read::<i32>()? },
+            "PlayerList" => BungeeResponse::PlayerList { server: input.java_utf()?, players: split_list(&input.java_utf()?) },
+            "GetServers" => BungeeResponse::GetServers { servers: split_list(&input.java_utf()?) },
+            "GetServer" => BungeeResponse::GetServer { server: input.java_utf()? },
+            "UUID" => BungeeResponse::Uuid { uuid: parse_uuid(&input.java_utf()?)? },
+            "UUIDOther" => BungeeResponse::UuidOther { player: input.java_utf()?, uuid: parse_uuid(&input.java_utf()?)? },
+            "ServerIP" => BungeeResponse::ServerIp { server: input.java_utf()?, ip: input.java_utf()?, port: input.read::<u16>()? },
+            _ => BungeeResponse::Forward { channel: subchannel, data: input.java_bytes()? },
+        };
+        Ok(response)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = ByteWriter::default();
+        match self {
+            BungeeResponse::Ip { ip, port } => out.java_utf("IP")?.java_utf(ip)?.write(port),
+            BungeeResponse::IpOther { player, ip, port } => out.java_utf("IPOther")?.java_utf(player)?.java_utf(ip)?.write(port),
+            BungeeResponse::PlayerCount { server, count } => out.java_utf("PlayerCount")?.java_utf(server)?.write(count),
+            BungeeResponse::PlayerList { server, players } => out.java_utf("PlayerList")?.java_utf(server)?.java_utf(&players.join(", "))?,
+            BungeeResponse::GetServers { servers } => out.java_utf("GetServers")?.java_utf(&servers.join(", "))?,
+            BungeeResponse::GetServer { server } => out.java_utf("GetServer")?.java_utf(server)?,
+            BungeeResponse::Uuid { uuid } => out.java_utf("UUID")?.java_utf(&undashed(*uuid))?,
+            BungeeResponse::UuidOther { player, uuid } => out.java_utf("UUIDOther")?.java_utf(player)?.java_utf(&undashed(*uuid))?,
+            BungeeResponse::ServerIp { server, ip, port } => out.java_utf("ServerIP")?.java_utf(server)?.java_utf(ip)?.write(port),
+            BungeeResponse::Forward { channel, data } => out.java_utf(channel)?.java_bytes(data)?,
+        };
+        Ok(out.into_inner())
+    }
+}
+
+fn split_list(raw: &str) -> Vec<String> {
+    raw.split(", ")
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// What a proxy has to provide to answer `bungeecord:main` requests. `player` is always a
+/// username; a player not on the proxy is `None` (or a no-op for actions).
+#[async_trait]
+pub trait BungeeProxy: Send + Sync + 'static {
+    async fn connect(&self, player: &str, server: &str) -> Result<()>;
+
+    async fn player_address(&self, player: &str) -> Result<Option<SocketAddr>>;
+
+    async fn player_uuid(&self, player: &str) -> Result<Option<UUID4>>;
+
+    async fn player_server(&self, player: &str) -> Result<Option<String>>;
+
+    /// The players on `server`, or on the whole proxy for `None`.
+    async fn players(&self, server: Option<&str>) -> Result<Vec<String>>;
+
+    async fn servers(&self) -> Result<Vec<String>>;
+
+    async fn server_address(&self, server: &str) -> Result<Option<SocketAddr>>;
+
+    /// Sends a chat message to `player`, or to everyone for `None`. `json` tells whether the
+    /// message is a JSON chat component or legacy (`§`-formatted) text.
+    async fn send_message(&self, player: Option<&str>, message: &str, json: bool) -> Result<()>;
+
+    /// Delivers `BungeeResponse::Forward { channel, data }` to the servers in `target`.
+    async fn forward(&self, target: &ServerTarget, channel: &str, data: &[u8]) -> Result<()>;
+
+    /// Delivers `BungeeResponse::Forward { channel, data }` to the server `player` is on.
+    async fn forward_to_player(&self, player: &str, channel: &str, data: &[u8]) -> Result<()>;
+
+    async fn kick(&self, player: &str, reason: &str) -> Result<()>;
+}
+
+/// Carries out a request which arrived through `player`'s connection, returning the response
+/// to send back to the same backend, if the request has one.
+pub async fn dispatch<P: BungeeProxy + ?Sized>(proxy: &P, player: &str, request: BungeeRequest) -> Result<Option<BungeeResponse>> {
+    let response = match request {
+        BungeeRequest::Connect { server } => {
+            proxy.connect(player, &server).await?;
+            None
+        }
+        BungeeRequest::ConnectOther { player, server } => {
+            proxy.connect(&player, &server).await?;
+            None
+        }
+        BungeeRequest::Ip => proxy.player_address(player).await?
+            .map(|addr| BungeeResponse::Ip { ip: addr.ip().to_string(), port: addr.port() as i32 }),
+        BungeeRequest::IpOther { player } => proxy.player_address(&player).await?
+            .map(|addr| BungeeResponse::IpOther { player, ip: addr.ip().to_string(), port: addr.port() as i32 }),
+        BungeeRequest::PlayerCount { server } => {
+            let players = proxy.players(server_filter(&server)).await?;
+            Some(BungeeResponse::PlayerCount { server: server.as_str().to_owned(), count: players.len() as i32 })
+        }
+        BungeeRequest::PlayerList { server } => {
+            let players = proxy.players(server_filter(&server)).await?;
+            Some(BungeeResponse::PlayerList { server: server.as_str().to_owned(), players })
+        }
+        BungeeRequest::GetServers => Some(BungeeResponse::GetServers { servers: proxy.servers().await? }),
+        BungeeRequest::GetServer => proxy.player_server(player).await?
+            .map(|server| BungeeResponse::GetServer { server }),
+        BungeeRequest::Message { player, message } => {
+            proxy.send_message(player_filter(&player), &message, false).await?;
+            None
+        }
+        BungeeRequest::MessageRaw { player, json } => {
+            proxy.send_message(player_filter(&player), &json, true).await?;
+            None
+        }
+        BungeeRequest::Forward { server, channel, data } => {
+            proxy.forward(&server, &channel, &data).await?;
+            None
+        }
+        BungeeRequest::ForwardToPlayer { player, channel, data } => {
+            proxy.forward_to_player(&player, &channel, &data).await?;
+            None
+        }
+        BungeeRequest::Uuid => proxy.player_uuid(player).await?
+            .map(|uuid| BungeeResponse::Uuid { uuid }),
+        BungeeRequest::UuidOther { player } => proxy.player_uuid(&player).await?
+            .map(|uuid| BungeeResponse::UuidOther { player, uuid }),
+        BungeeRequest::ServerIp { server } => proxy.server_address(&server).await?
+            .map(|addr| BungeeResponse::ServerIp { server, ip: addr.ip().to_string(), port: addr.port() }),
+        BungeeRequest::KickPlayer { player, reason } => {
+            proxy.kick(&player, &reason).await?;
+            None
+        }
+    };
+    Ok(response)
+}
+
+fn server_filter(target: &ServerTarget) -> Option<&str> {
+    match target {
+        ServerTarget::Server(name) => Some(name),
+        _ => None,
+    }
+}
+
+fn player_filter(player: &str) -> Option<&str> {
+    if player == "ALL" {
+        None
+    } else {
+        Some(player)
+    }
+}
+
+/// A `PacketHook` which answers `bungeecord:main` messages sent by the upstream through this
+/// relay's player, and passes every other packet to `inner`. Bungee messages are never forwarded
+/// to the client, like BungeeCord does, and a request which fails keeps the relay running.
+pub struct BungeeHook<P: ?Sized, H> {
+    proxy: Arc<P>,
+    player: String,
+    inner: H,
+}
+
+impl<P: BungeeProxy + ?Sized, H: PacketHook> BungeeHook<P, H> {
+    pub fn new(proxy: Arc<P>, player: impl Into<String>, inner: H) -> Self {
+        Self {
+            proxy,
+            player: player.into(),
+            inner,
+        }
+    }
+}
+
+#[async_trait]
+impl<P: BungeeProxy + ?Sized, H: PacketHook> PacketHook for BungeeHook<P, H> {
+    async fn on_packet(&self, packet: &RawPacket578<'_>, relay: &RelayHandle) -> Result<Action> {
+        if packet.id().direction != PacketDirection::ClientBound {
+            return self.inner.on_packet(packet, relay).await;
+        }
+
+        let (channel, data) = match plugin_message(packet)? {
+            Some((channel, data)) if channel == BUNGEE_CHANNEL || channel == LEGACY_BUNGEE_CHANNEL => (channel, data),
+            _ => return self.inner.on_packet(packet, relay).await,
+        };
+
+        // like BungeeCord, requests we can't decode (unknown subchannels included) or answer are
+        // dropped, as they come from a backend plugin rather than a broken connection
+        let response = async {
+            let request = BungeeRequest::decode(data)?;
+            match dispatch(self.proxy.as_ref(), &self.player, request).await? {
+                Some(response) => response.encode().map(Some),
+                None => Ok(None),
+            }
+        }.await;
+
+        if let Ok(Some(response)) = response {
+            relay.send_to_upstream(Packet578::PlayClientPluginMessage(PlayClientPluginMessageSpec {
+                channel,
+                data: RemainingBytes { data: response },
+            })).await?;
+        }
+        Ok(Action::Drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<M: PluginMessage + PartialEq + std::fmt::Debug>(message: M) {
+        assert_eq!(M::decode(&message.encode().unwrap()).unwrap(), message);
+    }
+
+    #[test]
+    fn requests_match_java_data_output() {
+        let connect = BungeeRequest::Connect { server: "lobby".to_owned() };
+        assert_eq!(connect.encode().unwrap(), b"\x00\x07Connect\x00\x05lobby");
+        let forward = BungeeRequest::Forward {
+            server: ServerTarget::Online,
+            channel: "Sync".to_owned(),
+            data: vec![1, 2, 3],
+        };
+        assert_eq!(forward.encode().unwrap(), b"\x00\x07Forward\x00\x06ONLINE\x00\x04Sync\x00\x03\x01\x02\x03");
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        round_trip(BungeeRequest::Connect { server: "lobby".to_owned() });
+        round_trip(BungeeRequest::ConnectOther { player: "Notch".to_owned(), server: "lobby".to_owned() });
+        round_trip(BungeeRequest::Ip);
+        round_trip(BungeeRequest::PlayerCount { server: ServerTarget::All });
+        round_trip(BungeeRequest::PlayerList { server: ServerTarget::Server("survival".to_owned()) });
+        round_trip(BungeeRequest::Forward {
+            server: ServerTarget::Online,
+            channel: "Sync".to_owned(),
+            data: vec![0, 1, 255],
+        });
+        round_trip(BungeeRequest::ForwardToPlayer {
+            player: "Notch".to_owned(),
+            channel: "Sync".to_owned(),
+            data: Vec::new(),
+        });
+        round_trip(BungeeRequest::Uuid);
+        round_trip(BungeeRequest::UuidOther { player: "Notch".to_owned() });
+        round_trip(BungeeRequest::ServerIp { server: "lobby".to_owned() });
+        round_trip(BungeeRequest::KickPlayer { player: "Notch".to_owned(), reason: "§cbye".to_owned() });
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let uuid = UUID4::from(0x069a79f4_44e9_4726_a5be_fca90e38aaf5_u128);
+        round_trip(BungeeResponse::Ip { ip: "192.0.2.1".to_owned(), port: 51234 });
+        round_trip(BungeeResponse::PlayerCount { server: "ALL".to_owned(), count: 42 });
+        round_trip(BungeeResponse::PlayerList { server: "lobby".to_owned(), players: vec!["Notch".to_owned(), "jeb_".to_owned()] });
+        round_trip(BungeeResponse::PlayerList { server: "empty".to_owned(), players: Vec::new() });
+        round_trip(BungeeResponse::GetServers { servers: vec!["lobby".to_owned(), "survival".to_owned()] });
+        round_trip(BungeeResponse::Uuid { uuid });
+        round_trip(BungeeResponse::UuidOther { player: "Notch".to_owned(), uuid });
+        round_trip(BungeeResponse::ServerIp { server: "lobby".to_owned(), ip: "10.0.0.2".to_owned(), port: 25566 });
+        round_trip(BungeeResponse::Forward { channel: "Sync".to_owned(), data: vec![9, 8, 7] });
+    }
+
+    #[test]
+    fn responses_too_long_for_java_fail_to_encode() {
+        let players = vec!["a".repeat(16); 5000];
+        assert!(BungeeResponse::PlayerList { server: "ALL".to_owned(), players }.encode().is_err());
+        let data = vec![0; u16::MAX as usize];
+        assert!(BungeeResponse::Forward { channel: "Sync".to_owned(), data }.encode().is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_and_truncated_requests() {
+        assert!(BungeeRequest::decode(b"\x00\x04Nope").is_err());
+        assert!(BungeeRequest::decode(b"\x00\x07Connect\x00\x05lob").is_err());
+    }
+}
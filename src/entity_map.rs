@@ -0,0 +1,320 @@
+use mcproto_rs::protocol::{Id, PacketDirection, State};
+use anyhow::Result;
+use super::util::{ByteReader, ByteWriter};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+// 1.15.2 entity types whose Spawn Entity object data is the id of the entity that shot them, the
+// same set BungeeCord remaps
+const ARROW: i32 = 2;
+const SPECTRAL_ARROW: i32 = 72;
+const FISHING_BOBBER: i32 = 102;
+
+/// Swaps the client's own entity id with the current upstream's entity id in 1.15.2 Play packets,
+/// like BungeeCord's EntityMap.
+///
+/// A client keeps the entity id it was given by the first JoinGame for the whole session, but
+/// every upstream it is moved to assigns a new one. Any packet that refers to the client's id in
+/// one id space must refer to the other id in the other space, and vice versa, which makes the
+/// rewrite the same swap in both directions.
+#[derive(Default)]
+pub struct EntityMap {
+    client_id: AtomicI32,
+    server_id: AtomicI32,
+    remapping: AtomicBool,
+}
+
+impl EntityMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called with the entity id from the first JoinGame the client sees.
+    pub fn set_client_id(&self, id: i32) {
+        self.remapping.store(false, Ordering::SeqCst);
+        self.client_id.store(id, Ordering::SeqCst);
+        self.server_id.store(id, Ordering::SeqCst);
+    }
+
+    /// Called with the entity id from the JoinGame of each upstream the client is moved to.
+    pub fn set_server_id(&self, id: i32) {
+        self.server_id.store(id, Ordering::SeqCst);
+        self.remapping.store(id != self.client_id.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    pub fn client_id(&self) -> i32 {
+        self.client_id.load(Ordering::SeqCst)
+    }
+
+    pub fn server_id(&self) -> i32 {
+        self.server_id.load(Ordering::SeqCst)
+    }
+
+    /// Returns the rewritten packet body, or `None` if the packet does not need to change.
+    pub fn rewrite(&self, id: Id, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !self.remapping.load(Ordering::SeqCst) || id.state != State::Play {
+            return Ok(None);
+        }
+
+        let mut rewriter = Rewriter {
+            input: ByteReader::new(data),
+            out: ByteWriter::with_capacity(data.len() + 5),
+            changed: false,
+            ids: (self.client_id(), self.server_id()),
+        };
+
+        match (id.direction, id.id) {
+            // spawn entity: id, uuid, type, position, pitch and yaw, object data
+            (PacketDirection::ClientBound, 0x00) => {
+                rewriter.varint()?;
+                rewriter.skip(16)?;
+                let entity_type = rewriter.skip_varint()?;
+                rewriter.skip(26)?;
+                match entity_type {
+                    // arrows store their shooter's id plus one, so that zero means no shooter
+                    ARROW | SPECTRAL_ARROW => rewriter.int_offset(1)?,
+                    FISHING_BOBBER => rewriter.int()?,
+                    _ => {}
+                }
+            }
+            // spawn experience orb, spawn weather entity, spawn living entity, spawn painting,
+            // spawn player, entity animation, block break animation
+            (PacketDirection::ClientBound, 0x01..=0x06) | (PacketDirection::ClientBound, 0x09) => rewriter.varint()?,
+            // entity status
+            (PacketDirection::ClientBound, 0x1C) => rewriter.int()?,
+            // open horse window
+            (PacketDirection::ClientBound, 0x20) => {
+                rewriter.skip(1)?;
+                rewriter.skip_varint()?;
+                rewriter.int()?;
+            }
+            // entity position, entity position and rotation, entity rotation, entity movement
+            (PacketDirection::ClientBound, 0x29..=0x2C) => rewriter.varint()?,
+            // combat event
+            (PacketDirection::ClientBound, 0x33) => match rewriter.skip_varint()? {
+                // end combat: duration, entity id
+                1 => {
+                    rewriter.skip_varint()?;
+                    rewriter.int()?;
+                }
+                // entity dead: player id, entity id, message
+                2 => {
+                    rewriter.varint()?;
+                    rewriter.int()?;
+                }
+                _ => {}
+            },
+            // destroy entities
+            (PacketDirection::ClientBound, 0x38) => {
+                let count = rewriter.skip_varint()?;
+                for _ in 0..count {
+                    rewriter.varint()?;
+                }
+            }
+            // remove entity effect, entity head look, camera, entity metadata
+            (PacketDirection::ClientBound, 0x39) |
+            (PacketDirection::ClientBound, 0x3C) |
+            (PacketDirection::ClientBound, 0x3F) |
+            (PacketDirection::ClientBound, 0x44) => rewriter.varint()?,
+            // attach entity
+            (PacketDirection::ClientBound, 0x45) => {
+                rewriter.int()?;
+                rewriter.int()?;
+            }
+            // entity velocity, entity equipment
+            (PacketDirection::ClientBound, 0x46..=0x47) => rewriter.varint()?,
+            // set passengers
+            (PacketDirection::ClientBound, 0x4B) => {
+                rewriter.varint()?;
+                let count = rewriter.skip_varint()?;
+                for _ in 0..count {
+                    rewriter.varint()?;
+                }
+            }
+            // entity sound effect
+            (PacketDirection::ClientBound, 0x51) => {
+                rewriter.skip_varint()?;
+                rewriter.skip_varint()?;
+                rewriter.varint()?;
+            }
+            // collect item
+            (PacketDirection::ClientBound, 0x56) => {
+                rewriter.varint()?;
+                rewriter.varint()?;
+            }
+            // entity teleport, entity properties, entity effect
+            (PacketDirection::ClientBound, 0x57) |
+            (PacketDirection::ClientBound, 0x59..=0x5A) => rewriter.varint()?,
+            // query entity nbt
+            (PacketDirection::ServerBound, 0x0D) => {
+                rewriter.skip_varint()?;
+                rewriter.varint()?;
+            }
+            // interact entity, entity action
+            (PacketDirection::ServerBound, 0x0E) |
+            (PacketDirection::ServerBound, 0x1B) => rewriter.varint()?,
+            _ => return Ok(None),
+        }
+
+        Ok(rewriter.finish())
+    }
+}
+
+struct Rewriter<'a> {
+    input: ByteReader<'a>,
+    out: ByteWriter,
+    changed: bool,
+    ids: (i32, i32),
+}
+
+impl<'a> Rewriter<'a> {
+    fn swap(&mut self, id: i32) -> i32 {
+        let (client_id, server_id) = self.ids;
+        let swapped = if id == client_id {
+            server_id
+        } else if id == server_id {
+            client_id
+        } else {
+            id
+        };
+
+        self.changed |= swapped != id;
+        swapped
+    }
+
+    fn skip(&mut self, n: usize) -> Result<()> {
+        self.out.bytes(self.input.take(n)?);
+        Ok(())
+    }
+
+    fn skip_varint(&mut self) -> Result<i32> {
+        let value = self.input.varint()?;
+        self.out.varint(value);
+        Ok(value)
+    }
+
+    fn varint(&mut self) -> Result<()> {
+        let value = self.input.varint()?;
+        let value = self.swap(value);
+        self.out.varint(value);
+        Ok(())
+    }
+
+    fn int(&mut self) -> Result<()> {
+        let value = self.input.read::<i32>()?;
+        let value = self.swap(value);
+        self.out.write(&value);
+        Ok(())
+    }
+
+    /// An int holding an entity id plus `offset`, where smaller values mean no entity.
+    fn int_offset(&mut self, offset: i32) -> Result<()> {
+        let value = self.input.read::<i32>()?;
+        let value = if value < offset {
+            value
+        } else {
+            self.swap(value - offset) + offset
+        };
+        self.out.write(&value);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Option<Vec<u8>> {
+        if !self.changed {
+            return None;
+        }
+
+        self.out.bytes(self.input.remaining());
+        Some(self.out.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_ID: i32 = 7;
+    const SERVER_ID: i32 = 500;
+
+    fn play(direction: PacketDirection, id: i32) -> Id {
+        Id { id, state: State::Play, direction }
+    }
+
+    fn remapping() -> EntityMap {
+        let map = EntityMap::new();
+        map.set_client_id(CLIENT_ID);
+        map.set_server_id(SERVER_ID);
+        map
+    }
+
+    // entity position: id, three deltas, on ground
+    fn entity_position(entity_id: i32) -> Vec<u8> {
+        let mut out = ByteWriter::default();
+        out.varint(entity_id).write(&1i16).write(&2i16).write(&3i16).write(&true);
+        out.into_inner()
+    }
+
+    fn spawn_entity(entity_type: i32, data: i32) -> Vec<u8> {
+        let mut out = ByteWriter::default();
+        out.varint(42).bytes(&[0xAB; 16]).varint(entity_type).bytes(&[0; 26]).write(&data).bytes(&[0; 6]);
+        out.into_inner()
+    }
+
+    #[test]
+    fn nothing_changes_until_the_server_id_differs() {
+        let map = EntityMap::new();
+        map.set_client_id(CLIENT_ID);
+        assert_eq!(map.rewrite(play(PacketDirection::ClientBound, 0x29), &entity_position(CLIENT_ID)).unwrap(), None);
+        map.set_server_id(CLIENT_ID);
+        assert_eq!(map.rewrite(play(PacketDirection::ClientBound, 0x29), &entity_position(CLIENT_ID)).unwrap(), None);
+    }
+
+    #[test]
+    fn swaps_ids_in_both_directions() {
+        let map = remapping();
+        let id = play(PacketDirection::ClientBound, 0x29);
+        assert_eq!(map.rewrite(id, &entity_position(SERVER_ID)).unwrap(), Some(entity_position(CLIENT_ID)));
+        assert_eq!(map.rewrite(id, &entity_position(CLIENT_ID)).unwrap(), Some(entity_position(SERVER_ID)));
+        assert_eq!(map.rewrite(id, &entity_position(1234)).unwrap(), None);
+
+        // entity action: id, action, jump boost
+        let action = |entity_id| {
+            let mut out = ByteWriter::default();
+            out.varint(entity_id).varint(0).varint(0);
+            out.into_inner()
+        };
+        let id = play(PacketDirection::ServerBound, 0x1B);
+        assert_eq!(map.rewrite(id, &action(CLIENT_ID)).unwrap(), Some(action(SERVER_ID)));
+    }
+
+    #[test]
+    fn ignores_other_states_and_packets() {
+        let map = remapping();
+        let login = Id { id: 0x29, state: State::Login, direction: PacketDirection::ClientBound };
+        assert_eq!(map.rewrite(login, &entity_position(SERVER_ID)).unwrap(), None);
+        // chat message
+        assert_eq!(map.rewrite(play(PacketDirection::ClientBound, 0x0F), &entity_position(SERVER_ID)).unwrap(), None);
+    }
+
+    #[test]
+    fn rewrites_spawn_entity_owners() {
+        let map = remapping();
+        let id = play(PacketDirection::ClientBound, 0x00);
+        // arrow and spectral arrow
+        assert_eq!(map.rewrite(id, &spawn_entity(2, SERVER_ID + 1)).unwrap(), Some(spawn_entity(2, CLIENT_ID + 1)));
+        assert_eq!(map.rewrite(id, &spawn_entity(72, SERVER_ID + 1)).unwrap(), Some(spawn_entity(72, CLIENT_ID + 1)));
+        // an arrow without a shooter
+        assert_eq!(map.rewrite(id, &spawn_entity(2, 0)).unwrap(), None);
+        // fishing bobber
+        assert_eq!(map.rewrite(id, &spawn_entity(102, SERVER_ID)).unwrap(), Some(spawn_entity(102, CLIENT_ID)));
+        // object data of other types (armor stand, player, trident) is not remapped
+        assert_eq!(map.rewrite(id, &spawn_entity(1, SERVER_ID)).unwrap(), None);
+        assert_eq!(map.rewrite(id, &spawn_entity(101, SERVER_ID + 1)).unwrap(), None);
+        assert_eq!(map.rewrite(id, &spawn_entity(83, SERVER_ID + 1)).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_packets_fail() {
+        let map = remapping();
+        assert!(map.rewrite(play(PacketDirection::ClientBound, 0x00), &spawn_entity(2, 0)[..20]).is_err());
+    }
+}
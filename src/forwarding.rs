@@ -0,0 +1,350 @@
+use super::{
+    TcpConnection,
+    auth::{GameProfile, ProfileProperty, parse_uuid, undashed},
+    login::{LoginResult, read_login_start, finish_login},
+    login_plugin::{LoginPluginHandler, LoginPluginRequests},
+    metrics::{self, FailureKind, FailureStage},
+    util::{ByteReader, ByteWriter},
+};
+use mcproto_rs::{types::Chat, uuid::UUID4};
+use anyhow::Result;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::{fmt, net::{IpAddr, SocketAddr}};
+
+/// Profile property BungeeGuard adds to the forwarded properties to prove the connection came
+/// through a trusted proxy.
+pub const BUNGEEGUARD_TOKEN_PROPERTY: &str = "bungeeguard-token";
+
+/// Player information a proxy forwards to its backends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForwardedPlayer {
+    /// The host the client connected to the proxy with.
+    pub host: String,
+    pub address: IpAddr,
+    pub uuid: UUID4,
+    pub properties: Vec<ProfileProperty>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForwardingError {
+    /// The handshake does not contain forwarding data, which means the client connected without
+    /// going through a proxy (or the proxy has forwarding disabled).
+    NotForwarded,
+    Malformed(String),
+    /// BungeeGuard is enabled but the token is missing or not one of the allowed tokens.
+    InvalidToken,
+}
+
+impl fmt::Display for ForwardingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardingError::NotForwarded => f.write_str("handshake was not forwarded by a proxy"),
+            ForwardingError::Malformed(reason) => write!(f, "malformed forwarding data: {}", reason),
+            ForwardingError::InvalidToken => f.write_str("missing or invalid BungeeGuard token"),
+        }
+    }
+}
+
+impl std::error::Error for ForwardingError {}
+
+/// Builds the Handshake `server_address` BungeeCord sends to backends with `ip_forward` enabled:
+/// `host\0ip\0uuid` followed by `\0properties` when there are any. Setting `guard_token` adds
+/// the BungeeGuard token to the properties.
+pub fn encode_bungee(player: &ForwardedPlayer, guard_token: Option<&str>) -> Result<String> {
+    let mut properties = player.properties.clone();
+    if let Some(token) = guard_token {
+        properties.push(ProfileProperty {
+            name: BUNGEEGUARD_TOKEN_PROPERTY.to_owned(),
+            value: token.to_owned(),
+            signature: None,
+        });
+    }
+
+    let mut out = format!("{}\0{}\0{}", player.host, player.address, undashed(player.uuid));
+    if !properties.is_empty() {
+        out.push('\0');
+        out.push_str(&serde_json::to_string(&properties)?);
+    }
+    Ok(out)
+}
+
+/// Parses a Handshake `server_address` built by `encode_bungee`.
+///
+/// When `guard_tokens` is set, exactly one BungeeGuard token must be present and it must be one
+/// of the allowed tokens. The token is removed from the returned properties either way, so it is
+/// never passed on to clients.
+pub fn decode_bungee(server_address: &str, guard_tokens: Option<&[String]>) -> Result<ForwardedPlayer, ForwardingError> {
+    let parts: Vec<&str> = server_address.split('\0').collect();
+    if parts.len() < 3 {
+        return Err(ForwardingError::NotForwarded);
+    }
+
+    if parts.len() > 4 {
+        return Err(ForwardingError::Malformed(format!("expected at most 4 fields, got {}", parts.len())));
+    }
+
+    let address = parts[1].parse()
+        .map_err(|_| ForwardingError::Malformed(format!("invalid address {:?}", parts[1])))?;
+    let uuid = parse_uuid(parts[2])
+        .map_err(|_| ForwardingError::Malformed(format!("invalid uuid {:?}", parts[2])))?;
+    let mut properties: Vec<ProfileProperty> = match parts.get(3) {
+        Some(raw) => serde_json::from_str(raw)
+            .map_err(|err| ForwardingError::Malformed(format!("invalid properties: {}", err)))?,
+        None => Vec::new(),
+    };
+
+    let mut tokens = Vec::new();
+    properties.retain(|property| if property.name == BUNGEEGUARD_TOKEN_PROPERTY {
+        tokens.push(property.value.clone());
+        false
+    } else {
+        true
+    });
+
+    if let Some(allowed) = guard_tokens {
+        if tokens.len() != 1 || !allowed.iter().any(|token| token == &tokens[0]) {
+            return Err(ForwardingError::InvalidToken);
+        }
+    }
+
+    Ok(ForwardedPlayer {
+        host: parts[0].to_owned(),
+        address,
+        uuid,
+        properties,
+    })
+}
+
+/// Login plugin channel Velocity uses for modern forwarding.
+pub const VELOCITY_CHANNEL: &str = "velocity:player_info";
+pub const VELOCITY_FORWARDING_VERSION: i32 = 1;
+const VELOCITY_SIGNATURE_LEN: usize = 32;
+const VELOCITY_REJECTED_MESSAGE: &str = "This server requires you to connect with Velocity.";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What Velocity forwards: the client's real address and its (authenticated) profile.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VelocityForwarded {
+    pub address: IpAddr,
+    pub profile: GameProfile,
+}
+
+/// Proxy side of Velocity modern forwarding: answers `velocity:player_info` requests from a
+/// backend with `player`, signed with the shared secret. Register it on `LoginOptions::plugins`
+/// under `VELOCITY_CHANNEL`.
+#[derive(Clone)]
+pub struct VelocityForwarding {
+    pub secret: Vec<u8>,
+    pub player: VelocityForwarded,
+}
+
+#[async_trait]
+impl LoginPluginHandler for VelocityForwarding {
+    async fn handle(&self, _data: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(Some(encode_velocity(&self.secret, &self.player)))
+    }
+}
+
+/// Builds the LoginPluginResponse payload for `velocity:player_info`: an HMAC-SHA256 signature
+/// (keyed with the shared secret) followed by the signed forwarding data.
+pub fn encode_velocity(secret: &[u8], forwarded: &VelocityForwarded) -> Vec<u8> {
+    let mut data = ByteWriter::with_capacity(256);
+    data.varint(VELOCITY_FORWARDING_VERSION)
+        .string(&forwarded.address.to_string())
+        .write(&forwarded.profile.id)
+        .string(&forwarded.profile.name)
+        .varint(forwarded.profile.properties.len() as i32);
+    for property in &forwarded.profile.properties {
+        data.string(&property.name).string(&property.value);
+        match &property.signature {
+            Some(signature) => data.write(&true).string(signature),
+            None => data.write(&false),
+        };
+    }
+
+    let data = data.into_inner();
+    let mut mac = velocity_mac(secret);
+    mac.update(&data);
+    let mut out = mac.finalize().into_bytes().to_vec();
+    out.extend_from_slice(&data);
+    out
+}
+
+/// Verifies and parses a payload built by `encode_velocity`.
+pub fn decode_velocity(secret: &[u8], payload: &[u8]) -> Result<VelocityForwarded, ForwardingError> {
+    if payload.len() < VELOCITY_SIGNATURE_LEN {
+        return Err(ForwardingError::Malformed("forwarding data is too short to be signed".to_owned()));
+    }
+
+    let (signature, data) = payload.split_at(VELOCITY_SIGNATURE_LEN);
+    let mut mac = velocity_mac(secret);
+    mac.update(data);
+    if mac.verify(signature).is_err() {
+        return Err(ForwardingError::InvalidToken);
+    }
+
+    let malformed = |err: anyhow::Error| ForwardingError::Malformed(err.to_string());
+    let mut reader = ByteReader::new(data);
+    let version = reader.varint().map_err(malformed)?;
+    if version != VELOCITY_FORWARDING_VERSION {
+        return Err(ForwardingError::Malformed(format!("unsupported forwarding version {}", version)));
+    }
+
+    let address: String = reader.read().map_err(malformed)?;
+    let address = address.parse()
+        .map_err(|_| ForwardingError::Malformed(format!("invalid address {:?}", address)))?;
+    let id: UUID4 = reader.read().map_err(malformed)?;
+    let name = reader.read().map_err(malformed)?;
+    let count = reader.varint().map_err(malformed)?;
+    let mut profile = GameProfile::new(id, name);
+    for _ in 0..count {
+        let name = reader.read().map_err(malformed)?;
+        let value = reader.read().map_err(malformed)?;
+        let signature = if reader.read::<bool>().map_err(malformed)? {
+            Some(reader.read().map_err(malformed)?)
+        } else {
+            None
+        };
+        profile.properties.push(ProfileProperty { name, value, signature });
+    }
+
+    Ok(VelocityForwarded { address, profile })
+}
+
+fn velocity_mac(secret: &[u8]) -> HmacSha256 {
+    HmacSha256::new_varkey(secret).expect("hmac accepts keys of any length")
+}
+
+/// Backend side of Velocity modern forwarding, for a connection in the Login state which has
+/// not read its LoginStart yet. Asks the proxy for the forwarding data, verifies it with the
+/// shared secret and completes the login as the forwarded player (with the forwarded uuid).
+///
+/// The connection's `remote_addr` is replaced with the forwarded address. Clients which did not
+/// come through Velocity are disconnected.
+pub async fn accept_velocity_login(conn: &mut TcpConnection, secret: &[u8], compression_threshold: Option<i32>) -> Result<(LoginResult, VelocityForwarded)> {
+    read_login_start(conn).await?;
+    let forwarded = match LoginPluginRequests::new().request(conn, VELOCITY_CHANNEL, Vec::new()).await? {
+        Some(data) => decode_velocity(secret, &data),
+        None => Err(ForwardingError::NotForwarded),
+    };
+
+    let forwarded = match forwarded {
+        Ok(forwarded) => forwarded,
+        Err(err) => {
+            let message = match err {
+                ForwardingError::NotForwarded => VELOCITY_REJECTED_MESSAGE,
+                _ => "Unable to verify player details",
+            };
+            metrics::global().record_failure_kind(FailureStage::Login, FailureKind::Forwarding);
+            conn.disconnect(Chat::from_text(message)).await?;
+            return Err(err.into());
+        }
+    };
+
+    let port = conn.remote_addr().map(|addr| addr.port()).unwrap_or(0);
+    conn.set_remote_addr(SocketAddr::new(forwarded.address, port));
+    let result = finish_login(conn, LoginResult {
+        name: forwarded.profile.name.clone(),
+        uuid: forwarded.profile.id,
+        properties: forwarded.profile.properties.clone(),
+    }, compression_threshold).await?;
+    Ok((result, forwarded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn textures() -> ProfileProperty {
+        ProfileProperty {
+            name: "textures".to_owned(),
+            value: "ewogICJ0aW1lc3RhbXAiIDogMAp9".to_owned(),
+            signature: Some("c2lnbmF0dXJl".to_owned()),
+        }
+    }
+
+    fn bungee_player(properties: Vec<ProfileProperty>) -> ForwardedPlayer {
+        ForwardedPlayer {
+            host: "play.example.com".to_owned(),
+            address: "203.0.113.5".parse().unwrap(),
+            uuid: UUID4::from(0x069a79f4_44e9_4726_a5be_fca90e38aaf5_u128),
+            properties,
+        }
+    }
+
+    #[test]
+    fn bungee_round_trip() {
+        let player = bungee_player(Vec::new());
+        let encoded = encode_bungee(&player, None).unwrap();
+        assert_eq!(encoded, "play.example.com\0203.0.113.5\0069a79f444e94726a5befca90e38aaf5");
+        assert_eq!(decode_bungee(&encoded, None), Ok(player));
+
+        let player = bungee_player(vec![textures()]);
+        let encoded = encode_bungee(&player, None).unwrap();
+        assert_eq!(decode_bungee(&encoded, None), Ok(player));
+        assert_eq!(decode_bungee("play.example.com", None), Err(ForwardingError::NotForwarded));
+    }
+
+    #[test]
+    fn bungee_guard_tokens() {
+        let player = bungee_player(vec![textures()]);
+        let allowed = vec!["token".to_owned()];
+        let encoded = encode_bungee(&player, Some("token")).unwrap();
+        assert_eq!(decode_bungee(&encoded, Some(&allowed)), Ok(player.clone()));
+        // the token is stripped even when not checked
+        assert_eq!(decode_bungee(&encoded, None), Ok(player.clone()));
+
+        let encoded = encode_bungee(&player, Some("wrong")).unwrap();
+        assert_eq!(decode_bungee(&encoded, Some(&allowed)), Err(ForwardingError::InvalidToken));
+        let encoded = encode_bungee(&player, None).unwrap();
+        assert_eq!(decode_bungee(&encoded, Some(&allowed)), Err(ForwardingError::InvalidToken));
+    }
+
+    fn velocity_player() -> VelocityForwarded {
+        let id = UUID4::from(0x069a79f4_44e9_4726_a5be_fca90e38aaf5_u128);
+        VelocityForwarded {
+            address: "2001:db8::7".parse().unwrap(),
+            profile: GameProfile::new(id, "Notch")
+                .with_property(textures())
+                .with_property(ProfileProperty {
+                    name: "unsigned".to_owned(),
+                    value: "value".to_owned(),
+                    signature: None,
+                }),
+        }
+    }
+
+    #[test]
+    fn velocity_round_trip() {
+        let player = velocity_player();
+        let payload = encode_velocity(b"secret", &player);
+        assert_eq!(decode_velocity(b"secret", &payload), Ok(player));
+    }
+
+    #[test]
+    fn velocity_rejects_bad_signatures() {
+        let mut payload = encode_velocity(b"secret", &velocity_player());
+        assert_eq!(decode_velocity(b"other secret", &payload), Err(ForwardingError::InvalidToken));
+
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        assert_eq!(decode_velocity(b"secret", &payload), Err(ForwardingError::InvalidToken));
+    }
+
+    #[test]
+    fn velocity_rejects_short_payloads() {
+        let payload = encode_velocity(b"secret", &velocity_player());
+        assert!(matches!(decode_velocity(b"secret", &payload[..16]), Err(ForwardingError::Malformed(_))));
+
+        // correctly signed, but cut off in the middle of the data
+        let data = &payload[VELOCITY_SIGNATURE_LEN..VELOCITY_SIGNATURE_LEN + 4];
+        let mut mac = velocity_mac(b"secret");
+        mac.update(data);
+        let mut truncated = mac.finalize().into_bytes().to_vec();
+        truncated.extend_from_slice(data);
+        assert!(matches!(decode_velocity(b"secret", &truncated), Err(ForwardingError::Malformed(_))));
+    }
+}
@@ -0,0 +1,169 @@
+use super::{TcpConnection, TcpWriteBridge, util::read_deserialized};
+use mcproto_rs::v1_15_2::{Packet578, PlayClientKeepAliveSpec, PlayServerKeepAliveSpec};
+use anyhow::Result;
+use tokio::time::{self, Instant};
+use std::{fmt, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// How long vanilla servers wait for a KeepAlive reply before kicking.
+pub const DEFAULT_SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the vanilla client waits for any packet before giving up on the server.
+pub const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returned (inside the `anyhow::Error`) by `KeepAlive::read_packet`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeepAliveError {
+    /// The client did not answer a KeepAlive in time (server side), or the server sent nothing
+    /// at all for too long (client side).
+    TimedOut(Duration),
+    /// The client answered with an id we did not send.
+    UnexpectedId(i64),
+}
+
+impl fmt::Display for KeepAliveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeepAliveError::TimedOut(after) => write!(f, "no keep alive from peer for {:?}", after),
+            KeepAliveError::UnexpectedId(id) => write!(f, "keep alive reply with unexpected id {}", id),
+        }
+    }
+}
+
+impl std::error::Error for KeepAliveError {}
+
+enum Role {
+    /// Answers the server's KeepAlives. Any packet from the server shows it is alive.
+    Client {
+        last_received: Instant,
+    },
+    /// Sends KeepAlives every interval and waits for each reply.
+    Server {
+        interval: Duration,
+        next_send: Instant,
+        // (id, sent at)
+        pending: Option<(i64, Instant)>,
+    },
+}
+
+/// Handles KeepAlive packets for a Play state connection, in place of reading packets directly.
+///
+/// `read_packet` returns every packet except KeepAlives. While it waits for one, it answers
+/// KeepAlives (client side) or sends them on an interval (server side), and fails with a
+/// `KeepAliveError` when the peer goes silent for longer than the timeout. On the client side
+/// any packet resets the timeout, like in vanilla; on the server side only the KeepAlive reply
+/// does. The connection should be closed after such an error, as the read in progress was
+/// abandoned.
+pub struct KeepAlive {
+    role: Role,
+    timeout: Duration,
+    latency: Option<Duration>,
+}
+
+impl KeepAlive {
+    /// For connections to a server.
+    pub fn client() -> Self {
+        Self {
+            role: Role::Client {
+                last_received: Instant::now(),
+            },
+            timeout: DEFAULT_CLIENT_TIMEOUT,
+            latency: None,
+        }
+    }
+
+    /// For connections accepted from a client.
+    pub fn server() -> Self {
+        Self {
+            role: Role::Server {
+                interval: DEFAULT_KEEP_ALIVE_INTERVAL,
+                next_send: Instant::now() + DEFAULT_KEEP_ALIVE_INTERVAL,
+                pending: None,
+            },
+            timeout: DEFAULT_SERVER_TIMEOUT,
+            latency: None,
+        }
+    }
+
+    /// How often a server sends KeepAlives. Has no effect on the client side.
+    pub fn with_interval(mut self, every: Duration) -> Self {
+        if let Role::Server { interval, next_send, .. } = &mut self.role {
+            *interval = every;
+            *next_send = Instant::now() + every;
+        }
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The round trip time measured by the last answered KeepAlive (server side only).
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Reads the next packet which is not a KeepAlive.
+    pub async fn read_packet(&mut self, conn: &mut TcpConnection) -> Result<Option<Packet578>> {
+        loop {
+            let packet = {
+                let writer = &mut conn.writer;
+                // the read is kept across timer ticks rather than restarted, because dropping it
+                // part way through a packet would lose data
+                let read = read_deserialized(&mut conn.reader);
+                tokio::pin!(read);
+                loop {
+                    let deadline = self.next_deadline();
+                    tokio::select! {
+                        read = &mut read => break read?,
+                        _ = time::delay_until(deadline) => self.on_deadline(writer).await?,
+                    }
+                }
+            };
+
+            if let (Role::Client { last_received }, Some(_)) = (&mut self.role, &packet) {
+                *last_received = Instant::now();
+            }
+
+            match (&mut self.role, packet) {
+                (Role::Client { .. }, Some(Packet578::PlayServerKeepAlive(body))) => {
+                    conn.write_packet(Packet578::PlayClientKeepAlive(PlayClientKeepAliveSpec {
+                        id: body.id,
+                    })).await?;
+                }
+                (Role::Server { pending, .. }, Some(Packet578::PlayClientKeepAlive(body))) => match pending.take() {
+                    Some((id, sent_at)) if id == body.id => self.latency = Some(sent_at.elapsed()),
+                    _ => return Err(KeepAliveError::UnexpectedId(body.id).into()),
+                },
+                (_, packet) => return Ok(packet),
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Instant {
+        match &self.role {
+            Role::Client { last_received } => *last_received + self.timeout,
+            Role::Server { pending: Some((_, sent_at)), .. } => *sent_at + self.timeout,
+            Role::Server { next_send, .. } => *next_send,
+        }
+    }
+
+    async fn on_deadline(&mut self, writer: &mut TcpWriteBridge) -> Result<()> {
+        match &mut self.role {
+            Role::Client { last_received } => Err(KeepAliveError::TimedOut(last_received.elapsed()).into()),
+            Role::Server { pending: Some((_, sent_at)), .. } => Err(KeepAliveError::TimedOut(sent_at.elapsed()).into()),
+            Role::Server { interval, next_send, pending } => {
+                let now = Instant::now();
+                // vanilla uses the current time in milliseconds as the id
+                let id = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as i64)
+                    .unwrap_or(0);
+                writer.write_packet(Packet578::PlayServerKeepAlive(PlayServerKeepAliveSpec { id })).await?;
+                *pending = Some((id, now));
+                *next_send = now + *interval;
+                Ok(())
+            }
+        }
+    }
+}
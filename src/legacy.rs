@@ -0,0 +1,194 @@
+use super::TcpConnection;
+use mcproto_rs::status::StatusSpec;
+use anyhow::{Result, anyhow};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+    time,
+};
+use std::time::Duration;
+
+/// First byte of every pre-1.7 server list ping. A modern client never starts a connection with it.
+pub const LEGACY_PING_ID: u8 = 0xFE;
+const LEGACY_PING_PAYLOAD: u8 = 0x01;
+const LEGACY_PLUGIN_MESSAGE_ID: u8 = 0xFA;
+const LEGACY_KICK_ID: u8 = 0xFF;
+const PING_HOST_CHANNEL: &str = "MC|PingHost";
+// 1.6.4, the last version to use the legacy ping
+const LEGACY_PROTOCOL_VERSION: u8 = 74;
+// older clients send nothing after 0xFE (or 0xFE01), so we can't wait long for the optional parts
+const LEGACY_READ_GRACE: Duration = Duration::from_millis(250);
+
+/// What a 1.6 client tells us about itself in its `MC|PingHost` message. Older clients don't send
+/// one, in which case every field is `None`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LegacyPing {
+    pub protocol_version: Option<u8>,
+    pub host: Option<String>,
+    pub port: Option<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LegacyStatus {
+    pub protocol_version: i32,
+    pub version_name: String,
+    pub motd: String,
+    pub online: i32,
+    pub max: i32,
+}
+
+impl LegacyStatus {
+    pub fn from_status(status: &StatusSpec) -> Self {
+        let (protocol_version, version_name) = match &status.version {
+            Some(version) => (version.protocol, version.name.clone()),
+            None => (0, String::new()),
+        };
+
+        Self {
+            protocol_version,
+            version_name,
+            motd: status.description.to_traditional().unwrap_or_default(),
+            online: status.players.online,
+            max: status.players.max,
+        }
+    }
+
+    fn encode(&self) -> String {
+        format!("§1\0{}\0{}\0{}\0{}\0{}", self.protocol_version, self.version_name, self.motd, self.online, self.max)
+    }
+
+    fn decode(raw: &str) -> Result<Self> {
+        if let Some(rest) = raw.strip_prefix("§1\0") {
+            let parts: Vec<&str> = rest.split('\0').collect();
+            if parts.len() != 5 {
+                return Err(anyhow!("legacy ping response has {} fields, expected 5", parts.len()));
+            }
+
+            return Ok(Self {
+                protocol_version: parts[0].parse()?,
+                version_name: parts[1].to_owned(),
+                motd: parts[2].to_owned(),
+                online: parts[3].parse()?,
+                max: parts[4].parse()?,
+            });
+        }
+
+        // beta 1.8 - 1.3 style: motd§online§max
+        let mut parts = raw.rsplitn(3, '§');
+        let max = parts.next().ok_or_else(|| anyhow!("legacy ping response missing max players"))?.parse()?;
+        let online = parts.next().ok_or_else(|| anyhow!("legacy ping response missing online players"))?.parse()?;
+        let motd = parts.next().unwrap_or_default().to_owned();
+        Ok(Self {
+            protocol_version: 0,
+            version_name: String::new(),
+            motd,
+            online,
+            max,
+        })
+    }
+}
+
+impl TcpConnection {
+    /// Returns true if the peer opened the connection with a legacy server list ping rather than a
+    /// Handshake. Must be called before the first packet is read.
+    pub async fn is_legacy_ping(&mut self) -> Result<bool> {
+        Ok(self.reader.peek_byte().await? == Some(LEGACY_PING_ID))
+    }
+}
+
+/// Reads a legacy ping from a connection (see `TcpConnection::is_legacy_ping`), answers it with
+/// the kick packet pre-1.7 clients expect and closes the connection.
+pub async fn respond_legacy(conn: TcpConnection, status: &LegacyStatus) -> Result<LegacyPing> {
+    let (mut reader, mut writer) = conn.into_inner();
+    if reader.read_u8().await? != LEGACY_PING_ID {
+        return Err(anyhow!("connection did not start with a legacy ping"));
+    }
+
+    let ping = match time::timeout(LEGACY_READ_GRACE, read_ping_body(&mut reader)).await {
+        Ok(ping) => ping?,
+        Err(_) => LegacyPing::default(),
+    };
+
+    writer.write_all(&encode_kick(&status.encode())).await?;
+    writer.flush().await?;
+    let _ = writer.shutdown().await;
+    Ok(ping)
+}
+
+async fn read_ping_body<R: AsyncRead + Unpin>(reader: &mut R) -> Result<LegacyPing> {
+    let mut out = LegacyPing::default();
+    if reader.read_u8().await? != LEGACY_PING_PAYLOAD {
+        return Ok(out);
+    }
+
+    if reader.read_u8().await? != LEGACY_PLUGIN_MESSAGE_ID {
+        return Ok(out);
+    }
+
+    let channel = read_utf16(reader).await?;
+    if channel != PING_HOST_CHANNEL {
+        return Err(anyhow!("unexpected legacy ping channel {:?}", channel));
+    }
+
+    // length of the rest of the message, which we parse field by field instead
+    reader.read_u16().await?;
+    out.protocol_version = Some(reader.read_u8().await?);
+    out.host = Some(read_utf16(reader).await?);
+    out.port = Some(reader.read_i32().await?);
+    Ok(out)
+}
+
+/// Sends a 1.6-style legacy ping and parses the response. Servers from beta 1.8 onwards answer
+/// this, which makes it useful for monitoring servers of mixed versions.
+pub async fn ping_legacy<A: ToSocketAddrs>(target: A, timeout: Duration) -> Result<LegacyStatus> {
+    match time::timeout(timeout, ping_legacy_inner(target)).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("legacy ping timed out after {:?}", timeout)),
+    }
+}
+
+async fn ping_legacy_inner<A: ToSocketAddrs>(target: A) -> Result<LegacyStatus> {
+    let mut stream = TcpStream::connect(target).await?;
+    stream.set_nodelay(true)?;
+    let peer = stream.peer_addr()?;
+
+    let mut host_data = Vec::new();
+    host_data.push(LEGACY_PROTOCOL_VERSION);
+    write_utf16(&mut host_data, &peer.ip().to_string());
+    host_data.extend_from_slice(&(peer.port() as i32).to_be_bytes());
+
+    let mut request = vec![LEGACY_PING_ID, LEGACY_PING_PAYLOAD, LEGACY_PLUGIN_MESSAGE_ID];
+    write_utf16(&mut request, PING_HOST_CHANNEL);
+    request.extend_from_slice(&(host_data.len() as u16).to_be_bytes());
+    request.extend_from_slice(&host_data);
+    stream.write_all(&request).await?;
+
+    let packet_id = stream.read_u8().await?;
+    if packet_id != LEGACY_KICK_ID {
+        return Err(anyhow!("expected legacy kick (0xFF) but got {:#04x}", packet_id));
+    }
+
+    LegacyStatus::decode(&read_utf16(&mut stream).await?)
+}
+
+fn encode_kick(message: &str) -> Vec<u8> {
+    let mut out = vec![LEGACY_KICK_ID];
+    write_utf16(&mut out, message);
+    out
+}
+
+fn write_utf16(target: &mut Vec<u8>, value: &str) {
+    let units: Vec<u16> = value.encode_utf16().collect();
+    target.extend_from_slice(&(units.len() as u16).to_be_bytes());
+    for unit in units {
+        target.extend_from_slice(&unit.to_be_bytes());
+    }
+}
+
+async fn read_utf16<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
+    let len = reader.read_u16().await? as usize;
+    let mut raw = vec![0u8; len * 2];
+    reader.read_exact(&mut raw).await?;
+    let units: Vec<u16> = raw.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+    Ok(String::from_utf16(&units)?)
+}
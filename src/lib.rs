@@ -4,8 +4,31 @@ mod writer;
 mod bridge;
 mod util;
 mod net;
+pub mod auth;
+pub mod login;
+pub mod login_plugin;
+pub mod status;
+pub mod legacy;
+pub mod listener;
+pub mod limits;
+pub mod proxy;
+pub mod entity_map;
+pub mod vhost;
+pub mod passthrough;
+pub mod forwarding;
+pub mod proxy_protocol;
+pub mod plugin_channels;
+pub mod bungee_messaging;
+pub mod keep_alive;
+pub mod stats;
+pub mod metrics;
 
-pub use reader::ReadBridge;
+pub use reader::{ReadBridge, ReadTimeouts, ReadTimeoutError};
 pub use writer::WriteBridge;
 pub use bridge::Bridge;
-pub use net::{TcpConnection, TcpReadBridge, TcpWriteBridge};
\ No newline at end of file
+pub use net::{TcpConnection, TcpReadBridge, TcpWriteBridge};
+pub use auth::{SessionService, HttpSessionService, MockSessionService, GameProfile};
+pub use login::{accept_login, LoginResult, LoginOptions, LoginError};
+pub use status::{StatusProvider, StatusResponder};
+pub use listener::Listener;
+pub use limits::{ConnectionLimits, Cidr};
\ No newline at end of file
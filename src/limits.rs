@@ -0,0 +1,364 @@
+use anyhow::{Result, anyhow};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// vanilla's default connection-throttle
+pub const DEFAULT_THROTTLE: Duration = Duration::from_millis(4000);
+// once the throttle table grows past this many addresses we drop the expired entries
+const THROTTLE_PRUNE_AT: usize = 1024;
+
+/// An IPv4 or IPv6 network in CIDR notation, such as `10.0.0.0/8` or `2001:db8::/32`. A bare
+/// address is treated as a single host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(network: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max = max_prefix_len(&network);
+        if prefix_len > max {
+            return Err(anyhow!("prefix length {} is too long for {}", prefix_len, network));
+        }
+
+        // store the masked network so equal networks compare equal
+        let network = match network {
+            IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(u32::from(v4) & (mask(prefix_len, 32) as u32))),
+            IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask(prefix_len, 128))),
+        };
+        Ok(Self { network, prefix_len })
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, canonical(addr)) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = mask(self.prefix_len, 32) as u32;
+                u32::from(addr) & mask == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                u128::from(addr) & mask(self.prefix_len, 128) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, '/');
+        let network: IpAddr = parts.next().unwrap_or_default().trim().parse()?;
+        let prefix_len = match parts.next() {
+            Some(len) => len.trim().parse()?,
+            None => max_prefix_len(&network),
+        };
+        Self::new(network, prefix_len)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+fn max_prefix_len(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn mask(prefix_len: u8, bits: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        (u128::MAX << (bits - prefix_len)) & (u128::MAX >> (128 - bits as u32))
+    }
+}
+
+// dual stack sockets report IPv4 peers as ::ffff:a.b.c.d, which should match IPv4 rules
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            if segments[..5].iter().all(|s| *s == 0) && segments[5] == 0xffff {
+                IpAddr::V4(Ipv4Addr::new(
+                    (segments[6] >> 8) as u8, segments[6] as u8,
+                    (segments[7] >> 8) as u8, segments[7] as u8))
+            } else {
+                addr
+            }
+        }
+        v4 => v4,
+    }
+}
+
+/// Limits applied by the `Listener` to every accepted socket, before anything is read from it.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionLimits {
+    /// Minimum time between two connections from the same address.
+    pub throttle: Option<Duration>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// When not empty, only addresses inside one of these networks are accepted.
+    pub allow: Vec<Cidr>,
+    /// Addresses inside any of these networks are rejected. Takes priority over `allow`.
+    pub deny: Vec<Cidr>,
+}
+
+impl ConnectionLimits {
+    /// Vanilla's behavior: only the connection throttle is enabled.
+    pub fn vanilla() -> Self {
+        Self {
+            throttle: Some(DEFAULT_THROTTLE),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    Denied,
+    Throttled,
+    TooManyConnections,
+    TooManyConnectionsFromIp,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rejection::Denied => "address is not allowed to connect",
+            Rejection::Throttled => "connection throttled",
+            Rejection::TooManyConnections => "too many connections",
+            Rejection::TooManyConnectionsFromIp => "too many connections from this address",
+        })
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+#[derive(Default)]
+struct LimiterState {
+    last_seen: HashMap<IpAddr, Instant>,
+    active: usize,
+    active_per_ip: HashMap<IpAddr, usize>,
+}
+
+pub(crate) struct Limiter {
+    limits: ConnectionLimits,
+    state: Mutex<LimiterState>,
+}
+
+impl Limiter {
+    pub fn new(limits: ConnectionLimits) -> Arc<Self> {
+        Arc::new(Self {
+            limits,
+            state: Mutex::new(LimiterState::default()),
+        })
+    }
+
+    /// Checks and counts against `max_connections` only. The limits which depend on the client
+    /// address are checked by `Reservation::admit`, once the address is known.
+    pub fn reserve(self: &Arc<Self>) -> Result<Reservation, Rejection> {
+        let mut state = self.state.lock().expect("limiter state poisoned");
+        if let Some(max) = self.limits.max_connections {
+            if state.active >= max {
+                return Err(Rejection::TooManyConnections);
+            }
+        }
+
+        state.active += 1;
+        Ok(Reservation {
+            limiter: Some(self.clone()),
+        })
+    }
+
+    fn admit_addr(&self, addr: IpAddr) -> Result<(), Rejection> {
+        let limits = &self.limits;
+        if limits.deny.iter().any(|cidr| cidr.contains(addr)) {
+            return Err(Rejection::Denied);
+        }
+
+        if !limits.allow.is_empty() && !limits.allow.iter().any(|cidr| cidr.contains(addr)) {
+            return Err(Rejection::Denied);
+        }
+
+        let mut state = self.state.lock().expect("limiter state poisoned");
+        if let Some(throttle) = limits.throttle {
+            let now = Instant::now();
+            if state.last_seen.len() >= THROTTLE_PRUNE_AT {
+                state.last_seen.retain(|_, seen| now.duration_since(*seen) < throttle);
+            }
+
+            if let Some(previous) = state.last_seen.insert(addr, now) {
+                if now.duration_since(previous) < throttle {
+                    return Err(Rejection::Throttled);
+                }
+            }
+        }
+
+        let from_addr = state.active_per_ip.get(&addr).cloned().unwrap_or(0);
+        if let Some(max) = limits.max_connections_per_ip {
+            if from_addr >= max {
+                return Err(Rejection::TooManyConnectionsFromIp);
+            }
+        }
+
+        state.active_per_ip.insert(addr, from_addr + 1);
+        Ok(())
+    }
+
+    fn release(&self, addr: Option<IpAddr>) {
+        let mut state = self.state.lock().expect("limiter state poisoned");
+        state.active -= 1;
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return,
+        };
+
+        let remove = match state.active_per_ip.get_mut(&addr) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+
+        if remove {
+            state.active_per_ip.remove(&addr);
+        }
+    }
+}
+
+/// A slot under `max_connections`, held until the client address is known and the remaining
+/// limits can be checked with `admit`. Dropping it frees the slot.
+pub(crate) struct Reservation {
+    limiter: Option<Arc<Limiter>>,
+}
+
+impl Reservation {
+    pub fn admit(mut self, addr: IpAddr) -> Result<ConnectionPermit, Rejection> {
+        let addr = canonical(addr);
+        let limiter = self.limiter.take().expect("reservation admitted twice");
+        if let Err(rejection) = limiter.admit_addr(addr) {
+            // dropping the reservation frees the slot
+            self.limiter = Some(limiter);
+            return Err(rejection);
+        }
+
+        Ok(ConnectionPermit { limiter, addr })
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.limiter.take() {
+            limiter.release(None);
+        }
+    }
+}
+
+/// Counts towards the connection limits for as long as it is alive. Connections yielded by the
+/// `Listener` carry their permit, so dropping the connection releases it.
+pub struct ConnectionPermit {
+    limiter: Arc<Limiter>,
+    addr: IpAddr,
+}
+
+impl ConnectionPermit {
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limiter.release(Some(self.addr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_parses_networks_and_hosts() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(cidr.network(), ip("10.0.0.0"));
+        assert_eq!(cidr.prefix_len(), 8);
+        assert_eq!(cidr, "10.0.0.0/8".parse().unwrap());
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+
+        let host: Cidr = "192.168.1.7".parse().unwrap();
+        assert_eq!(host.prefix_len(), 32);
+        let v6: Cidr = "2001:db8::1".parse().unwrap();
+        assert_eq!(v6.prefix_len(), 128);
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("not an address/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(ip("10.255.0.1")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert!(!cidr.contains(ip("2001:db8::1")));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn cidr_matches_ipv4_mapped_addresses() {
+        let cidr: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(cidr.contains(ip("::ffff:192.168.4.20")));
+        assert!(!cidr.contains(ip("::ffff:10.0.0.1")));
+    }
+
+    #[test]
+    fn limiter_counts_reservations_and_permits() {
+        let limiter = Limiter::new(ConnectionLimits {
+            max_connections: Some(2),
+            max_connections_per_ip: Some(1),
+            deny: vec!["10.0.0.0/8".parse().unwrap()],
+            ..ConnectionLimits::default()
+        });
+
+        let first = limiter.reserve().unwrap().admit(ip("192.0.2.1")).unwrap();
+        assert_eq!(limiter.reserve().unwrap().admit(ip("::ffff:192.0.2.1")).err(), Some(Rejection::TooManyConnectionsFromIp));
+        assert_eq!(limiter.reserve().unwrap().admit(ip("10.0.0.1")).err(), Some(Rejection::Denied));
+
+        let pending = limiter.reserve().unwrap();
+        assert_eq!(limiter.reserve().err(), Some(Rejection::TooManyConnections));
+        drop(pending);
+        drop(first);
+        assert!(limiter.reserve().unwrap().admit(ip("192.0.2.1")).is_ok());
+    }
+}
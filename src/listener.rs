@@ -0,0 +1,443 @@
+use super::{
+    Bridge,
+    ReadTimeouts,
+    TcpConnection,
+    forwarding::{decode_bungee, ForwardingError},
+    legacy::{respond_legacy, LegacyStatus},
+    limits::{Cidr, ConnectionLimits, ConnectionPermit, Limiter, Rejection, Reservation},
+    metrics::{self, FailureKind, FailureStage},
+    proxy_protocol::read_proxy_header,
+    status::StatusProvider,
+    util::read_deserialized,
+};
+use mcproto_rs::{
+    protocol::State,
+    types::Chat,
+    v1_15_2::{Packet578, HandshakeSpec, HandshakeNextState},
+};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use tokio::{
+    io,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{mpsc, watch},
+    time::{self, Instant},
+};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// how long to stop accepting after running out of file descriptors, or other accept errors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// vanilla rejects handshakes with longer server addresses
+const MAX_SERVER_ADDRESS_LEN: usize = 255;
+// forwarding data (including skin properties) doesn't fit in 255, so Spigot allows this much
+const MAX_FORWARDED_SERVER_ADDRESS_LEN: usize = 32767;
+
+type Handshaken = (TcpConnection, HandshakeSpec);
+
+/// Settings for the per-connection handshake task.
+#[derive(Clone)]
+struct HandshakeOptions {
+    timeout: Duration,
+    legacy_status: Option<Arc<dyn StatusProvider>>,
+    bungee_forwarding: Option<BungeeForwarding>,
+    // the networks of the proxies allowed to send PROXY protocol headers
+    proxy_protocol: Option<Vec<Cidr>>,
+    read_timeouts: ReadTimeouts,
+}
+
+/// Requires logins to come through BungeeCord with `ip_forward` enabled, and optionally checks
+/// their BungeeGuard token.
+#[derive(Clone, Debug, Default)]
+pub struct BungeeForwarding {
+    pub guard_tokens: Option<Vec<String>>,
+}
+
+/// Accepts connections and reads their Handshake, yielding only connections that sent a valid
+/// one. The returned connection is already in the Status or Login state, as requested by the
+/// Handshake.
+///
+/// Handshakes are read concurrently (each on its own task), so a slow client never holds up the
+/// accept loop.
+pub struct Listener {
+    inner: TcpListener,
+    handshake: HandshakeOptions,
+    limiter: Arc<Limiter>,
+    shutdown_tx: Arc<watch::Sender<Option<Arc<ShutdownSignal>>>>,
+    shutdown_rx: watch::Receiver<Option<Arc<ShutdownSignal>>>,
+    ready_tx: mpsc::UnboundedSender<Handshaken>,
+    ready_rx: mpsc::UnboundedReceiver<Handshaken>,
+}
+
+impl Listener {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Ok(Self::from_listener(TcpListener::bind(addr).await?))
+    }
+
+    pub fn from_listener(inner: TcpListener) -> Self {
+        let (ready_tx, ready_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = watch::channel(None);
+        Self {
+            inner,
+            handshake: HandshakeOptions {
+                timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+                legacy_status: None,
+                bungee_forwarding: None,
+                proxy_protocol: None,
+                read_timeouts: ReadTimeouts::default(),
+            },
+            limiter: Limiter::new(ConnectionLimits::default()),
+            shutdown_tx: Arc::new(shutdown_tx),
+            shutdown_rx,
+            ready_tx,
+            ready_rx,
+        }
+    }
+
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake.timeout = timeout;
+        self
+    }
+
+    /// Answer pre-1.7 server list pings using this provider. Without one they are just closed.
+    pub fn with_legacy_status(mut self, provider: Arc<dyn StatusProvider>) -> Self {
+        self.handshake.legacy_status = Some(provider);
+        self
+    }
+
+    /// Accept logins only when their Handshake carries BungeeCord forwarding data (which must
+    /// pass the BungeeGuard check, if configured). Other logins are disconnected. The remote
+    /// address of accepted connections is set to the forwarded address, the forwarded player is
+    /// available from `TcpConnection::forwarded`, and the Handshake's `server_address` is cut
+    /// down to the host the client connected to.
+    pub fn with_bungee_forwarding(mut self, forwarding: BungeeForwarding) -> Self {
+        self.handshake.bungee_forwarding = Some(forwarding);
+        self
+    }
+
+    /// Require every connection to start with a HAProxy PROXY protocol (v1 or v2) header, as sent
+    /// by load balancers, and use the client address from it as the connection's remote address.
+    /// Connections without a valid header are closed.
+    ///
+    /// Anyone could send a header with a forged address, so only sockets from the `trusted_proxies`
+    /// networks are accepted at all. The `max_connections` cap is still checked on accept, but the
+    /// limits that depend on the client address (throttle, per-address cap, allow and deny lists)
+    /// are only checked once the header has been read.
+    pub fn with_proxy_protocol(mut self, trusted_proxies: Vec<Cidr>) -> Self {
+        self.handshake.proxy_protocol = Some(trusted_proxies);
+        self
+    }
+
+    /// Read timeouts for accepted connections. They stay in effect after the connection is
+    /// handed over, so handlers don't have to guard each read against dead peers.
+    pub fn with_read_timeouts(mut self, timeouts: ReadTimeouts) -> Self {
+        self.handshake.read_timeouts = timeouts;
+        self
+    }
+
+    /// Connection limits are checked as soon as a socket is accepted, and rejected sockets are
+    /// closed without reading anything from them.
+    pub fn with_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limiter = Limiter::new(limits);
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Errors that only concern the connection being accepted are skipped, and running out of
+    /// file descriptors pauses accepting for a moment instead of failing.
+    pub async fn accept(&mut self) -> io::Result<Handshaken> {
+        loop {
+            tokio::select! {
+                accepted = self.inner.accept() => {
+                    let (stream, addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) if is_connection_error(&err) => continue,
+                        Err(err) if is_out_of_descriptors(&err) => {
+                            time::delay_for(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                        Err(err) => return Err(err),
+                    };
+                    let admission = match &self.handshake.proxy_protocol {
+                        Some(trusted) if !trusted.iter().any(|cidr| cidr.contains(addr.ip())) => Err(Rejection::Denied),
+                        Some(_) => self.limiter.reserve().map(Admission::Reserved),
+                        None => self.limiter.reserve().and_then(|reservation| reservation.admit(addr.ip()).map(Admission::Admitted)),
+                    };
+                    match admission {
+                        Ok(admission) => self.spawn_handshake(stream, admission),
+                        Err(_) => metrics::global().record_failure_kind(FailureStage::Handshake, FailureKind::Rejected),
+                    }
+                }
+                Some(ready) = self.ready_rx.recv() => return Ok(ready),
+            }
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            tx: self.shutdown_tx.clone(),
+        }
+    }
+
+    /// Runs `handler` on its own task for every accepted connection, until shutdown is requested
+    /// through a `ShutdownHandle`. Accept errors never stop it, so live connections always get
+    /// the shutdown below.
+    ///
+    /// On shutdown the listener stops accepting, and every connection still being handled is sent
+    /// a Disconnect matching its state. Connections are closed once their Disconnect is flushed or
+    /// the shutdown deadline passes, whichever is first, and this returns once all are closed.
+    pub async fn serve<H: ConnectionHandler>(mut self, handler: Arc<H>) -> io::Result<()> {
+        let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        let signal = loop {
+            tokio::select! {
+                accepted = self.accept() => match accepted {
+                    Ok((conn, handshake)) => {
+                        let handler = handler.clone();
+                        let shutdown_rx = shutdown_rx.clone();
+                        let drain_tx = drain_tx.clone();
+                        tokio::spawn(handle_connection(conn, handshake, handler, shutdown_rx, drain_tx));
+                    }
+                    Err(_) => time::delay_for(ACCEPT_BACKOFF).await,
+                },
+                signal = wait_for_shutdown(&mut shutdown_rx) => break signal,
+            }
+        };
+
+        // stop accepting, then wait for every connection task to drop its drain sender
+        drop(self);
+        drop(drain_tx);
+        let _ = time::timeout_at(signal.deadline, drain_rx.recv()).await;
+        Ok(())
+    }
+
+    fn spawn_handshake(&self, stream: TcpStream, admission: Admission) {
+        let options = self.handshake.clone();
+        let ready_tx = self.ready_tx.clone();
+        tokio::spawn(async move {
+            let read = read_handshake(stream, admission, &options);
+            // connections which fail or time out during the handshake are dropped (closed) here
+            match time::timeout(options.timeout, read).await {
+                Ok(Ok(Some(ready))) => {
+                    let _ = ready_tx.send(ready);
+                }
+                Ok(Ok(None)) => {}
+                Ok(Err(err)) => metrics::global().record_failure(FailureStage::Handshake, &err),
+                Err(_) => metrics::global().record_failure_kind(FailureStage::Handshake, FailureKind::Timeout),
+            }
+        });
+    }
+}
+
+// the peer went away before we accepted its connection
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(err.kind(),
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::Interrupted)
+}
+
+// EMFILE and ENFILE (the same numbers on Linux, macOS and the BSDs), or WSAEMFILE on Windows.
+// These clear up once other connections close.
+fn is_out_of_descriptors(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(code) if cfg!(windows) => code == 10024,
+        Some(code) => code == 23 || code == 24,
+        None => false,
+    }
+}
+
+/// How far an accepted socket got through the connection limits.
+enum Admission {
+    Admitted(ConnectionPermit),
+    /// Only counted against `max_connections`, until the PROXY header gives the client address.
+    Reserved(Reservation),
+}
+
+async fn read_handshake(stream: TcpStream, admission: Admission, options: &HandshakeOptions) -> Result<Option<Handshaken>> {
+    stream.set_nodelay(true)?;
+    let mut conn = TcpConnection::from_client_connection(stream);
+    conn.set_read_timeouts(options.read_timeouts);
+    if options.proxy_protocol.is_some() {
+        let header = read_proxy_header(conn.reader.stream_mut()).await?;
+        if let Some(source) = header.source() {
+            conn.set_remote_addr(source);
+        }
+    }
+
+    let permit = match admission {
+        Admission::Admitted(permit) => permit,
+        Admission::Reserved(reservation) => {
+            let addr = conn.remote_addr().ok_or_else(|| anyhow!("connection has no remote address"))?;
+            reservation.admit(addr.ip())?
+        }
+    };
+    let mut conn = conn.with_permit(permit);
+    if conn.is_legacy_ping().await? {
+        if let Some(provider) = &options.legacy_status {
+            respond_legacy(conn, &LegacyStatus::from_status(&provider.status())).await?;
+        }
+        return Ok(None);
+    }
+
+    let mut handshake = match read_deserialized(&mut conn.reader).await? {
+        Some(Packet578::Handshake(body)) => body,
+        Some(other) => return Err(anyhow!("expected Handshake but got {:?}", other)),
+        None => return Ok(None),
+    };
+
+    let max_address_len = if options.bungee_forwarding.is_some() {
+        MAX_FORWARDED_SERVER_ADDRESS_LEN
+    } else {
+        MAX_SERVER_ADDRESS_LEN
+    };
+    if handshake.server_address.chars().count() > max_address_len {
+        return Err(anyhow!("handshake server address is too long"));
+    }
+
+    conn.set_state(match handshake.next_state {
+        HandshakeNextState::Status => State::Status,
+        HandshakeNextState::Login => State::Login,
+    });
+
+    if let Some(forwarding) = &options.bungee_forwarding {
+        if matches!(handshake.next_state, HandshakeNextState::Login) {
+            if let Err(err) = accept_bungee_forwarding(&mut conn, &mut handshake, forwarding) {
+                let message = match err {
+                    ForwardingError::NotForwarded => "If you wish to use IP forwarding, please enable it in your BungeeCord config as well!",
+                    _ => "Unable to authenticate - invalid forwarding data",
+                };
+                metrics::global().record_failure_kind(FailureStage::Handshake, FailureKind::Forwarding);
+                conn.disconnect(Chat::from_text(message)).await?;
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(Some((conn, handshake)))
+}
+
+fn accept_bungee_forwarding(conn: &mut TcpConnection, handshake: &mut HandshakeSpec, forwarding: &BungeeForwarding) -> Result<(), ForwardingError> {
+    let player = decode_bungee(&handshake.server_address, forwarding.guard_tokens.as_deref())?;
+    let port = conn.remote_addr().map(|addr| addr.port()).unwrap_or(0);
+    conn.set_remote_addr(SocketAddr::new(player.address, port));
+    // the rest of the address is the forwarding data, BungeeGuard token included, which must not
+    // reach handlers that log or route on the host
+    handshake.server_address = player.host.clone();
+    conn.set_forwarded(player);
+    Ok(())
+}
+
+/// Handles one connection accepted by `Listener::serve`. The connection is borrowed so that the
+/// listener can still disconnect it when shutting down.
+#[async_trait]
+pub trait ConnectionHandler: Send + Sync + 'static {
+    async fn handle(&self, conn: &mut TcpConnection, handshake: HandshakeSpec) -> Result<()>;
+}
+
+struct ShutdownSignal {
+    message: Chat,
+    deadline: Instant,
+}
+
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    tx: Arc<watch::Sender<Option<Arc<ShutdownSignal>>>>,
+}
+
+impl ShutdownHandle {
+    /// Asks `Listener::serve` to shut down, giving live connections up to `deadline` to receive
+    /// `message` before they are closed.
+    pub fn shutdown(&self, message: Chat, deadline: Duration) {
+        let _ = self.tx.broadcast(Some(Arc::new(ShutdownSignal {
+            message,
+            deadline: Instant::now() + deadline,
+        })));
+    }
+}
+
+async fn wait_for_shutdown(rx: &mut watch::Receiver<Option<Arc<ShutdownSignal>>>) -> Arc<ShutdownSignal> {
+    loop {
+        if let Some(signal) = rx.borrow().clone() {
+            return signal;
+        }
+
+        if rx.recv().await.is_none() {
+            // every handle (and the listener) is gone, so shutdown can never be requested
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+async fn handle_connection<H: ConnectionHandler>(
+    mut conn: TcpConnection,
+    handshake: HandshakeSpec,
+    handler: Arc<H>,
+    mut shutdown_rx: watch::Receiver<Option<Arc<ShutdownSignal>>>,
+    _drain: mpsc::Sender<()>,
+) {
+    let signal = tokio::select! {
+        _ = handler.handle(&mut conn, handshake) => return,
+        signal = wait_for_shutdown(&mut shutdown_rx) => signal,
+    };
+
+    // dropping the connection afterwards is the force-close
+    let _ = time::timeout_at(signal.deadline, conn.disconnect(signal.message.clone())).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_protocol::ProxyHeader;
+    use mcproto_rs::types::VarInt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn header() -> ProxyHeader {
+        ProxyHeader::Proxied {
+            source: "192.0.2.10:51234".parse().unwrap(),
+            destination: "198.51.100.1:25565".parse().unwrap(),
+        }
+    }
+
+    async fn send_handshake(addr: SocketAddr) -> Result<TcpConnection> {
+        let mut conn = TcpConnection::connect_to_server_with_proxy_header(addr, &header()).await?;
+        conn.write_packet(Packet578::Handshake(HandshakeSpec {
+            version: VarInt(578),
+            server_address: "localhost".to_owned(),
+            server_port: 25565,
+            next_state: HandshakeNextState::Status,
+        })).await?;
+        Ok(conn)
+    }
+
+    #[tokio::test]
+    async fn proxy_headers_from_trusted_proxies_set_the_remote_address() -> Result<()> {
+        let mut listener = Listener::bind("127.0.0.1:0").await?.with_proxy_protocol(vec!["127.0.0.0/8".parse()?]);
+        let client = tokio::spawn(send_handshake(listener.local_addr()?));
+        let (conn, _) = listener.accept().await?;
+        assert_eq!(conn.remote_addr(), header().source());
+        client.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_headers_from_other_peers_are_rejected() -> Result<()> {
+        let mut listener = Listener::bind("127.0.0.1:0").await?.with_proxy_protocol(vec!["10.0.0.0/8".parse()?]);
+        let addr = listener.local_addr()?;
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await?;
+            let _ = stream.write_all(&header().encode_v1()).await;
+            // the listener closes the socket without reading the header (which may reset it)
+            let mut buf = [0u8; 1];
+            Ok::<_, anyhow::Error>(matches!(stream.read(&mut buf).await, Ok(0) | Err(_)))
+        });
+
+        assert!(time::timeout(Duration::from_millis(500), listener.accept()).await.is_err());
+        assert!(client.await??);
+        Ok(())
+    }
+}
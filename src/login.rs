@@ -0,0 +1,315 @@
+use super::{
+    Bridge,
+    TcpConnection,
+    util::read_deserialized,
+    auth::{ProfileProperty, SessionService, server_hash, parse_uuid},
+    login_plugin::LoginPluginHandlers,
+    metrics::{self, FailureStage},
+};
+use mcproto_rs::{
+    protocol::{HasPacketId, Id, State},
+    types::{Chat, VarInt},
+    uuid::UUID4,
+    v1_15_2::{
+        Packet578,
+        HandshakeSpec,
+        HandshakeNextState,
+        LoginStartSpec,
+        LoginSetCompressionSpec,
+        LoginSuccessSpec,
+        LoginEncryptionResponseSpec,
+    },
+};
+use anyhow::{Result, anyhow};
+use md5::{Digest, Md5};
+use rand::rngs::OsRng;
+use rsa::{PaddingScheme, PublicKey, RSAPublicKey};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use std::{fmt, sync::Arc};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoginResult {
+    pub name: String,
+    pub uuid: UUID4,
+    /// The profile properties (such as skins) forwarded by a proxy. Empty otherwise.
+    pub properties: Vec<ProfileProperty>,
+}
+
+/// Runs the offline-mode login sequence on a connection which has just been moved to the Login
+/// state by its Handshake. On success both bridges are in the Play state.
+///
+/// Connections accepted with BungeeCord forwarding log in with the forwarded uuid and properties
+/// instead of the offline uuid.
+pub async fn accept_login(conn: &mut TcpConnection, compression_threshold: Option<i32>) -> Result<LoginResult> {
+    let result = async {
+        let name = read_login_start(conn).await?;
+        let (uuid, properties) = match conn.forwarded() {
+            Some(player) => (player.uuid, player.properties.clone()),
+            None => (offline_uuid(&name), Vec::new()),
+        };
+        finish_login(conn, LoginResult { name, uuid, properties }, compression_threshold).await
+    }.await;
+
+    if let Err(err) = &result {
+        metrics::global().record_failure(FailureStage::Login, err);
+    }
+    result
+}
+
+/// Reads the LoginStart a client sends first in the Login state, returning the username.
+pub async fn read_login_start(conn: &mut TcpConnection) -> Result<String> {
+    match read_deserialized(&mut conn.reader).await? {
+        Some(Packet578::LoginStart(body)) => Ok(body.name),
+        Some(other) => Err(anyhow!("expected LoginStart but got {:?}", other)),
+        None => Err(anyhow!("connection closed before LoginStart")),
+    }
+}
+
+/// Sends SetCompression (when a threshold is given) and LoginSuccess for `result`, and moves
+/// the connection to the Play state.
+pub async fn finish_login(conn: &mut TcpConnection, result: LoginResult, compression_threshold: Option<i32>) -> Result<LoginResult> {
+    if let Some(threshold) = compression_threshold {
+        conn.write_packet(Packet578::LoginSetCompression(LoginSetCompressionSpec {
+            threshold: VarInt(threshold),
+        })).await?;
+        // everything after SetCompression is compressed, in both directions
+        conn.set_compression_threshold(Some(threshold));
+    }
+
+    conn.write_packet(Packet578::LoginSuccess(LoginSuccessSpec {
+        uuid_string: result.uuid.to_string(),
+        username: result.name.clone(),
+    })).await?;
+    conn.set_state(State::Play);
+
+    Ok(result)
+}
+
+/// The uuid vanilla servers assign to players when running in offline mode, which is a version 3
+/// (name based) uuid of `OfflinePlayer:<name>`.
+pub fn offline_uuid(name: &str) -> UUID4 {
+    let mut hasher = Md5::new();
+    hasher.update(b"OfflinePlayer:");
+    hasher.update(name.as_bytes());
+    let mut bytes: [u8; 16] = hasher.finalize().into();
+    bytes[6] = (bytes[6] & 0x0f) | 0x30;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    UUID4::from(u128::from_be_bytes(bytes))
+}
+
+pub const DEFAULT_PROTOCOL_VERSION: i32 = 578;
+
+/// Credentials used to answer an EncryptionRequest from an online-mode server.
+#[derive(Clone)]
+pub struct ClientSession {
+    pub service: Arc<dyn SessionService>,
+    pub access_token: String,
+    pub profile: UUID4,
+}
+
+#[derive(Clone)]
+pub struct LoginOptions {
+    pub protocol_version: i32,
+    /// The host and port sent in the Handshake. Defaults to the address we connected to.
+    pub server_address: Option<(String, u16)>,
+    /// Required to log in to online-mode servers.
+    pub session: Option<ClientSession>,
+    /// Answers LoginPluginRequests, such as Velocity modern forwarding (register a
+    /// `forwarding::VelocityForwarding` under `VELOCITY_CHANNEL`). Requests on unregistered
+    /// channels are answered as not understood.
+    pub plugins: LoginPluginHandlers,
+}
+
+impl Default for LoginOptions {
+    fn default() -> Self {
+        Self {
+            protocol_version: DEFAULT_PROTOCOL_VERSION,
+            server_address: None,
+            session: None,
+            plugins: LoginPluginHandlers::new(),
+        }
+    }
+}
+
+/// Returned (inside the `anyhow::Error`) when a client login does not succeed.
+#[derive(Debug)]
+pub enum LoginError {
+    Disconnected(Chat),
+    EncryptionRequired,
+    UnexpectedPacket(Id),
+    ConnectionClosed,
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginError::Disconnected(reason) => write!(f, "disconnected during login: {:?}", reason),
+            LoginError::EncryptionRequired => f.write_str("server requested encryption but no session was configured"),
+            LoginError::UnexpectedPacket(id) => write!(f, "unexpected packet {:?} during login", id),
+            LoginError::ConnectionClosed => f.write_str("connection closed during login"),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+impl TcpConnection {
+    /// Connects to a server and logs in as `username`, returning a connection in the Play state.
+    ///
+    /// Failures caused by the server can be recovered with `err.downcast_ref::<LoginError>()`.
+    pub async fn login<A: ToSocketAddrs>(target: A, username: &str, options: LoginOptions) -> Result<(Self, LoginResult)> {
+        let result = Self::login_inner(target, username, options).await;
+        if let Err(err) = &result {
+            metrics::global().record_failure(FailureStage::UpstreamLogin, err);
+        }
+        result
+    }
+
+    async fn login_inner<A: ToSocketAddrs>(target: A, username: &str, options: LoginOptions) -> Result<(Self, LoginResult)> {
+        let stream = TcpStream::connect(target).await?;
+        stream.set_nodelay(true)?;
+        let peer = stream.peer_addr()?;
+        let mut conn = Self::from_server_connection(stream);
+
+        let (server_address, server_port) = options.server_address.clone()
+            .unwrap_or_else(|| (peer.ip().to_string(), peer.port()));
+        conn.write_packet(Packet578::Handshake(HandshakeSpec {
+            version: VarInt(options.protocol_version),
+            server_address,
+            server_port,
+            next_state: HandshakeNextState::Login,
+        })).await?;
+        conn.set_state(State::Login);
+
+        conn.write_packet(Packet578::LoginStart(LoginStartSpec {
+            name: username.to_owned(),
+        })).await?;
+
+        loop {
+            match read_deserialized(&mut conn.reader).await? {
+                Some(Packet578::LoginSetCompression(body)) => {
+                    let threshold = body.threshold.0;
+                    conn.set_compression_threshold(if threshold >= 0 { Some(threshold) } else { None });
+                }
+                Some(Packet578::LoginEncryptionRequest(body)) => {
+                    let session = options.session.as_ref().ok_or(LoginError::EncryptionRequired)?;
+                    let public_key: Vec<u8> = body.public_key.into();
+                    let verify_token: Vec<u8> = body.verify_token.into();
+                    let shared_secret: [u8; 16] = rand::random();
+
+                    let hash = server_hash(&body.server_id, &shared_secret, &public_key);
+                    session.service.join(&session.access_token, session.profile, &hash).await?;
+
+                    let key = RSAPublicKey::from_pkcs8(&public_key)?;
+                    let mut rng = OsRng;
+                    let encrypted_secret = key.encrypt(&mut rng, PaddingScheme::new_pkcs1v15_encrypt(), &shared_secret)?;
+                    let encrypted_token = key.encrypt(&mut rng, PaddingScheme::new_pkcs1v15_encrypt(), &verify_token)?;
+                    conn.write_packet(Packet578::LoginEncryptionResponse(LoginEncryptionResponseSpec {
+                        shared_secret: encrypted_secret.into(),
+                        verify_token: encrypted_token.into(),
+                    })).await?;
+                    conn.enable_encryption(&shared_secret, &shared_secret)?;
+                }
+                Some(Packet578::LoginPluginRequest(body)) => {
+                    let response = options.plugins.respond(&body).await?;
+                    conn.write_packet(Packet578::LoginPluginResponse(response)).await?;
+                }
+                Some(Packet578::LoginDisconnect(body)) => {
+                    return Err(LoginError::Disconnected(body.message).into());
+                }
+                Some(Packet578::LoginSuccess(body)) => {
+                    conn.set_state(State::Play);
+                    let uuid = parse_uuid(&body.uuid_string)?;
+                    return Ok((conn, LoginResult {
+                        name: body.username,
+                        uuid,
+                        properties: Vec::new(),
+                    }));
+                }
+                Some(other) => return Err(LoginError::UnexpectedPacket(other.id()).into()),
+                None => return Err(LoginError::ConnectionClosed.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{GameProfile, MockSessionService};
+    use mcproto_rs::v1_15_2::LoginEncryptionRequestSpec;
+    use rsa::{PublicKeyParts, RSAPrivateKey};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn offline_uuid_is_a_name_based_uuid() {
+        // as computed by UUID.nameUUIDFromBytes("OfflinePlayer:Notch")
+        assert_eq!(offline_uuid("Notch"), UUID4::from(0xb50ad385_829d_3141_a216_7e7d7539ba7f_u128));
+        assert_ne!(offline_uuid("Notch"), offline_uuid("notch"));
+    }
+
+    #[tokio::test]
+    async fn online_login_joins_through_the_session_service() -> Result<()> {
+        let profile_id = UUID4::from(0x069a79f4_44e9_4726_a5be_fca90e38aaf5_u128);
+        let mock = Arc::new(MockSessionService::new());
+        mock.register(GameProfile::new(profile_id, "Notch"));
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service = mock.clone();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut conn = TcpConnection::from_client_connection(stream);
+            match read_deserialized(&mut conn.reader).await? {
+                Some(Packet578::Handshake(_)) => {}
+                other => return Err(anyhow!("expected Handshake but got {:?}", other)),
+            }
+            conn.set_state(State::Login);
+            let name = read_login_start(&mut conn).await?;
+
+            let key = RSAPrivateKey::new(&mut OsRng, 1024)?;
+            let public_key = rsa_der::public_key_to_der(&key.n().to_bytes_be(), &key.e().to_bytes_be());
+            let verify_token = [1u8, 2, 3, 4];
+            conn.write_packet(Packet578::LoginEncryptionRequest(LoginEncryptionRequestSpec {
+                server_id: String::new(),
+                public_key: public_key.clone().into(),
+                verify_token: verify_token.to_vec().into(),
+            })).await?;
+
+            let response = match read_deserialized(&mut conn.reader).await? {
+                Some(Packet578::LoginEncryptionResponse(body)) => body,
+                other => return Err(anyhow!("expected EncryptionResponse but got {:?}", other)),
+            };
+            let encrypted_secret: Vec<u8> = response.shared_secret.into();
+            let encrypted_token: Vec<u8> = response.verify_token.into();
+            let shared_secret = key.decrypt(PaddingScheme::new_pkcs1v15_encrypt(), &encrypted_secret)?;
+            assert_eq!(key.decrypt(PaddingScheme::new_pkcs1v15_encrypt(), &encrypted_token)?, verify_token);
+            conn.enable_encryption(&shared_secret, &shared_secret)?;
+
+            let hash = server_hash("", &shared_secret, &public_key);
+            let profile = service.has_joined(&name, &hash, None).await?
+                .ok_or_else(|| anyhow!("{} did not join", name))?;
+            finish_login(&mut conn, LoginResult { name: profile.name, uuid: profile.id, properties: Vec::new() }, None).await?;
+            Ok::<_, anyhow::Error>(hash)
+        });
+
+        let options = LoginOptions {
+            session: Some(ClientSession {
+                service: mock.clone(),
+                access_token: "token".to_owned(),
+                profile: profile_id,
+            }),
+            ..Default::default()
+        };
+        let (_conn, result) = TcpConnection::login(addr, "Notch", options).await?;
+        let hash = server.await??;
+
+        assert_eq!(result.name, "Notch");
+        assert_eq!(result.uuid, profile_id);
+        let joins = mock.joins();
+        assert_eq!(joins.len(), 1);
+        assert_eq!(joins[0].server_hash, hash);
+        assert_eq!(joins[0].access_token, "token");
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use super::{TcpConnection, util::read_deserialized};
+use mcproto_rs::{
+    protocol::HasPacketId,
+    types::{RemainingBytes, VarInt},
+    v1_15_2::{Packet578, LoginPluginRequestSpec, LoginPluginResponseSpec},
+};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use tokio::sync::oneshot;
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Answers LoginPluginRequests on one channel, for the client side of a login.
+///
+/// Implemented for closures taking the request payload, for handlers which don't need to await
+/// anything.
+#[async_trait]
+pub trait LoginPluginHandler: Send + Sync + 'static {
+    /// Returns the response payload, or `None` to reply that the request was not understood.
+    async fn handle(&self, data: &[u8]) -> Result<Option<Vec<u8>>>;
+}
+
+#[async_trait]
+impl<F> LoginPluginHandler for F where F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static {
+    async fn handle(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok((self)(data))
+    }
+}
+
+/// Login channel handlers, keyed by channel name. Requests on channels without a handler are
+/// answered as not understood, like the vanilla client does.
+#[derive(Clone, Default)]
+pub struct LoginPluginHandlers {
+    handlers: HashMap<String, Arc<dyn LoginPluginHandler>>,
+}
+
+impl LoginPluginHandlers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(mut self, channel: impl Into<String>, handler: impl LoginPluginHandler) -> Self {
+        self.handlers.insert(channel.into(), Arc::new(handler));
+        self
+    }
+
+    pub fn handles(&self, channel: &str) -> bool {
+        self.handlers.contains_key(channel)
+    }
+
+    /// Runs the handler for `channel`, returning `None` when there is none or it did not
+    /// understand the request.
+    pub async fn handle(&self, channel: &str, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.handlers.get(channel) {
+            Some(handler) => handler.handle(data).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Builds the LoginPluginResponse for a request.
+    pub async fn respond(&self, request: &LoginPluginRequestSpec) -> Result<LoginPluginResponseSpec> {
+        let data = self.handle(&request.channel, &request.data.data).await?;
+        Ok(LoginPluginResponseSpec {
+            message_id: request.message_id.clone(),
+            successful: data.is_some(),
+            data: RemainingBytes { data: data.unwrap_or_default() },
+        })
+    }
+}
+
+/// The server side of login plugin messaging: sends LoginPluginRequests and matches the client's
+/// responses to them by message id.
+///
+/// Requests can be sent back to back without waiting. Responses are only read from the connection
+/// by `receive_responses`, which completes the futures returned by `send`.
+#[derive(Default)]
+pub struct LoginPluginRequests {
+    next_id: i32,
+    pending: HashMap<i32, oneshot::Sender<Option<Vec<u8>>>>,
+}
+
+impl LoginPluginRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Sends a request on `channel`. The returned future resolves to the response payload, or
+    /// `None` if the client did not understand the request.
+    pub async fn send(&mut self, conn: &mut TcpConnection, channel: &str, data: Vec<u8>) -> Result<LoginPluginResponse> {
+        let message_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        conn.write_packet(Packet578::LoginPluginRequest(LoginPluginRequestSpec {
+            message_id: VarInt(message_id),
+            channel: channel.to_owned(),
+            data: RemainingBytes { data },
+        })).await?;
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.insert(message_id, tx);
+        Ok(LoginPluginResponse(rx))
+    }
+
+    /// Reads responses until every request sent so far has been answered. Any other packet, or a
+    /// response to a message id which is not pending, is an error.
+    pub async fn receive_responses(&mut self, conn: &mut TcpConnection) -> Result<()> {
+        while !self.pending.is_empty() {
+            let response = match read_deserialized(&mut conn.reader).await? {
+                Some(Packet578::LoginPluginResponse(body)) => body,
+                Some(other) => return Err(anyhow!("expected LoginPluginResponse but got {:?}", other.id())),
+                None => return Err(anyhow!("connection closed while waiting for login plugin responses")),
+            };
+
+            let tx = self.pending.remove(&response.message_id.0)
+                .ok_or_else(|| anyhow!("login plugin response for unknown message id {}", response.message_id.0))?;
+            // the future may have been dropped by a caller which no longer cares
+            let _ = tx.send(if response.successful { Some(response.data.data) } else { None });
+        }
+        Ok(())
+    }
+
+    /// Sends one request and waits for its response.
+    pub async fn request(&mut self, conn: &mut TcpConnection, channel: &str, data: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let response = self.send(conn, channel, data).await?;
+        self.receive_responses(conn).await?;
+        response.await
+    }
+}
+
+/// The response to a request sent with `LoginPluginRequests::send`.
+pub struct LoginPluginResponse(oneshot::Receiver<Option<Vec<u8>>>);
+
+impl Future for LoginPluginResponse {
+    type Output = Result<Option<Vec<u8>>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|received| received.map_err(|_| anyhow!("login plugin request was dropped before it was answered")))
+    }
+}
@@ -0,0 +1,354 @@
+use super::{
+    ReadTimeoutError,
+    forwarding::ForwardingError,
+    keep_alive::KeepAliveError,
+    limits::Rejection,
+    login::LoginError,
+    stats::{PacketCounts, TrafficCounter, TrafficSnapshot, state_index, state_name},
+};
+use mcproto_rs::protocol::{PacketDirection, State};
+use anyhow::Result;
+use hyper::{Body, Method, Request, Response, Server, StatusCode, service::{make_service_fn, service_fn}};
+use once_cell::sync::Lazy;
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+const STATES: [State; 4] = [State::Handshaking, State::Status, State::Login, State::Play];
+const PEERS: [&str; 2] = ["client", "server"];
+const STAGES: [FailureStage; 3] = [FailureStage::Handshake, FailureStage::Login, FailureStage::UpstreamLogin];
+const FAILURE_KINDS: [FailureKind; 9] = [
+    FailureKind::Disconnected,
+    FailureKind::EncryptionRequired,
+    FailureKind::UnexpectedPacket,
+    FailureKind::ConnectionClosed,
+    FailureKind::Timeout,
+    FailureKind::Forwarding,
+    FailureKind::Rejected,
+    FailureKind::Io,
+    FailureKind::Other,
+];
+
+static GLOBAL: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// The metrics of every connection in this process.
+pub fn global() -> &'static Metrics {
+    &GLOBAL
+}
+
+/// What was being done when a connection failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureStage {
+    /// Accepting a connection and reading its Handshake.
+    Handshake,
+    /// Logging in a client which connected to us.
+    Login,
+    /// Logging in to an upstream server.
+    UpstreamLogin,
+}
+
+impl FailureStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureStage::Handshake => "handshake",
+            FailureStage::Login => "login",
+            FailureStage::UpstreamLogin => "upstream_login",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureKind {
+    Disconnected,
+    EncryptionRequired,
+    UnexpectedPacket,
+    ConnectionClosed,
+    Timeout,
+    Forwarding,
+    Rejected,
+    Io,
+    Other,
+}
+
+impl FailureKind {
+    /// Classifies an error returned by this crate by its type.
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<LoginError>() {
+            return match err {
+                LoginError::Disconnected(_) => FailureKind::Disconnected,
+                LoginError::EncryptionRequired => FailureKind::EncryptionRequired,
+                LoginError::UnexpectedPacket(_) => FailureKind::UnexpectedPacket,
+                LoginError::ConnectionClosed => FailureKind::ConnectionClosed,
+            };
+        }
+
+        if err.is::<ReadTimeoutError>() || err.is::<KeepAliveError>() {
+            FailureKind::Timeout
+        } else if err.is::<ForwardingError>() {
+            FailureKind::Forwarding
+        } else if err.is::<Rejection>() {
+            FailureKind::Rejected
+        } else if err.is::<std::io::Error>() {
+            FailureKind::Io
+        } else {
+            FailureKind::Other
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureKind::Disconnected => "disconnected",
+            FailureKind::EncryptionRequired => "encryption_required",
+            FailureKind::UnexpectedPacket => "unexpected_packet",
+            FailureKind::ConnectionClosed => "connection_closed",
+            FailureKind::Timeout => "timeout",
+            FailureKind::Forwarding => "forwarding",
+            FailureKind::Rejected => "rejected",
+            FailureKind::Io => "io",
+            FailureKind::Other => "other",
+        }
+    }
+}
+
+/// Process-wide counters, fed by every bridge and by the listener. Every counter is an atomic.
+///
+/// Counting can be switched off with `set_enabled`, which skips the per-packet work. The
+/// connection gauges are always kept, as they only change on connect, disconnect and state
+/// changes.
+#[derive(Default)]
+pub struct Metrics {
+    disabled: AtomicBool,
+    received: TrafficCounter,
+    sent: TrafficCounter,
+    // [peer][state]
+    connections: [[AtomicI64; 4]; 2],
+    encrypted_connections: AtomicI64,
+    encryption_enabled: AtomicU64,
+    compress_nanos: AtomicU64,
+    decompress_nanos: AtomicU64,
+    // [stage][kind]
+    failures: [[AtomicU64; 9]; 3],
+}
+
+impl Metrics {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.disabled.store(!enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.disabled.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> TrafficSnapshot {
+        self.received.snapshot()
+    }
+
+    pub fn sent(&self) -> TrafficSnapshot {
+        self.sent.snapshot()
+    }
+
+    /// Open connections in `state`, on either side.
+    pub fn connections(&self, state: &State) -> i64 {
+        self.connections.iter()
+            .map(|by_state| by_state[state_index(state)].load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Counts a failed handshake or login, classified by the type of `err`.
+    pub fn record_failure(&self, stage: FailureStage, err: &anyhow::Error) {
+        self.record_failure_kind(stage, FailureKind::of(err));
+    }
+
+    pub fn record_failure_kind(&self, stage: FailureStage, kind: FailureKind) {
+        if self.is_enabled() {
+            self.failures[stage as usize][kind as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Failures counted so far in `stage` of `kind`.
+    pub fn failures(&self, stage: FailureStage, kind: FailureKind) -> u64 {
+        self.failures[stage as usize][kind as usize].load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_packet(&self, direction: Traffic, state: &State, id: i32, wire_bytes: usize, data_bytes: usize) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let counter = match direction {
+            Traffic::Received => &self.received,
+            Traffic::Sent => &self.sent,
+        };
+        counter.record(state, id, wire_bytes, data_bytes);
+    }
+
+    pub(crate) fn record_compression(&self, spent: Duration) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        self.compress_nanos.fetch_add(spent.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_decompression(&self, spent: Duration) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        self.decompress_nanos.fetch_add(spent.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::with_capacity(4096);
+
+        header(&mut out, "mctokio_connections", "gauge", "Open connections by peer and state.");
+        for (peer, by_state) in PEERS.iter().zip(self.connections.iter()) {
+            for (state, count) in STATES.iter().zip(by_state.iter()) {
+                let _ = writeln!(out, "mctokio_connections{{peer=\"{}\",state=\"{}\"}} {}", peer, state_name(state), count.load(Ordering::Relaxed));
+            }
+        }
+
+        header(&mut out, "mctokio_encrypted_connections", "gauge", "Open connections with encryption enabled.");
+        let _ = writeln!(out, "mctokio_encrypted_connections {}", self.encrypted_connections.load(Ordering::Relaxed));
+        header(&mut out, "mctokio_encryption_enabled_total", "counter", "Connections which enabled encryption.");
+        let _ = writeln!(out, "mctokio_encryption_enabled_total {}", self.encryption_enabled.load(Ordering::Relaxed));
+
+        let traffic = [("received", self.received.snapshot()), ("sent", self.sent.snapshot())];
+        header(&mut out, "mctokio_bytes_total", "counter", "Bytes by direction, on the wire and before compression.");
+        for (direction, snapshot) in &traffic {
+            let _ = writeln!(out, "mctokio_bytes_total{{direction=\"{}\",kind=\"wire\"}} {}", direction, snapshot.total.wire_bytes);
+            let _ = writeln!(out, "mctokio_bytes_total{{direction=\"{}\",kind=\"data\"}} {}", direction, snapshot.total.data_bytes);
+        }
+
+        header(&mut out, "mctokio_packets_total", "counter", "Packets by direction, state and packet id.");
+        render_by_packet(&mut out, "mctokio_packets_total", &traffic, |counts| counts.packets);
+        header(&mut out, "mctokio_packet_bytes_total", "counter", "Wire bytes by direction, state and packet id.");
+        render_by_packet(&mut out, "mctokio_packet_bytes_total", &traffic, |counts| counts.wire_bytes);
+
+        header(&mut out, "mctokio_compression_seconds_total", "counter", "Time spent compressing and decompressing packets.");
+        for (operation, nanos) in &[("compress", &self.compress_nanos), ("decompress", &self.decompress_nanos)] {
+            let seconds = nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(out, "mctokio_compression_seconds_total{{operation=\"{}\"}} {}", operation, seconds);
+        }
+
+        header(&mut out, "mctokio_failures_total", "counter", "Failed handshakes and logins by error kind.");
+        for stage in &STAGES {
+            for kind in &FAILURE_KINDS {
+                let count = self.failures(*stage, *kind);
+                if count > 0 {
+                    let _ = writeln!(out, "mctokio_failures_total{{stage=\"{}\",kind=\"{}\"}} {}", stage.as_str(), kind.as_str(), count);
+                }
+            }
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn render_by_packet(out: &mut String, name: &str, traffic: &[(&str, TrafficSnapshot)], value: impl Fn(&PacketCounts) -> u64) {
+    for (direction, snapshot) in traffic {
+        let mut packets: Vec<_> = snapshot.by_packet.iter().collect();
+        packets.sort_by_key(|(key, _)| **key);
+        for (key, counts) in packets {
+            let _ = writeln!(out, "{}{{direction=\"{}\",state=\"{}\",id=\"{:#04x}\"}} {}", name, direction, key.state, key.id, value(counts));
+        }
+    }
+}
+
+/// Renders the global metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    global().render()
+}
+
+/// Serves the global metrics at `GET /metrics` on `addr`, until the server fails.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+            Ok::<_, Infallible>(respond(&request))
+        }))
+    });
+
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+fn respond(request: &Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::from("not found\n"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+
+    Response::builder()
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(Body::from(render()))
+        .expect("metrics response is valid")
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Traffic {
+    Received,
+    Sent,
+}
+
+/// Keeps the connection gauges up to date for one connection, from creation until it is dropped.
+/// Created with the `TcpConnection` and shared by both of its bridges (and by `splice`, which
+/// takes the sockets out of them), so it lives as long as any part of the connection does.
+pub(crate) struct ConnectionGauge {
+    peer: usize,
+    state: AtomicUsize,
+    encrypted: AtomicBool,
+}
+
+impl ConnectionGauge {
+    /// `read_direction` is the direction of the packets read from the peer.
+    pub fn new(read_direction: &PacketDirection) -> Self {
+        let peer = match read_direction {
+            PacketDirection::ServerBound => 0,
+            PacketDirection::ClientBound => 1,
+        };
+        let state = state_index(&State::Handshaking);
+        global().connections[peer][state].fetch_add(1, Ordering::Relaxed);
+        Self {
+            peer,
+            state: AtomicUsize::new(state),
+            encrypted: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_state(&self, state: &State) {
+        let next = state_index(state);
+        let previous = self.state.swap(next, Ordering::Relaxed);
+        if previous != next {
+            let connections = &global().connections[self.peer];
+            connections[previous].fetch_sub(1, Ordering::Relaxed);
+            connections[next].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_encrypted(&self) {
+        if !self.encrypted.swap(true, Ordering::Relaxed) {
+            global().encrypted_connections.fetch_add(1, Ordering::Relaxed);
+            global().encryption_enabled.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for ConnectionGauge {
+    fn drop(&mut self) {
+        global().connections[self.peer][self.state.load(Ordering::Relaxed)].fetch_sub(1, Ordering::Relaxed);
+        if self.encrypted.load(Ordering::Relaxed) {
+            global().encrypted_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
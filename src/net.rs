@@ -1,7 +1,22 @@
-use super::{ReadBridge, WriteBridge, Bridge};
-use mcproto_rs::protocol::{PacketDirection, Packet, RawPacket, State};
+use super::{
+    ReadBridge,
+    ReadTimeouts,
+    WriteBridge,
+    Bridge,
+    forwarding::ForwardedPlayer,
+    limits::ConnectionPermit,
+    metrics::ConnectionGauge,
+    proxy_protocol::ProxyHeader,
+    stats::TrafficSnapshot,
+};
+use mcproto_rs::{
+    protocol::{PacketDirection, Packet, RawPacket, State},
+    types::Chat,
+    v1_15_2::{Packet578, LoginDisconnectSpec, PlayDisconnectSpec},
+};
 use tokio::net::{ToSocketAddrs, TcpStream};
-use tokio::io;
+use tokio::{io::{self, AsyncWriteExt}, time};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 
 pub type TcpReadBridge = ReadBridge<io::BufReader<OwnedReadHalf>>;
@@ -11,9 +26,14 @@ pub type TcpWriteBridge = WriteBridge<OwnedWriteHalf>;
 pub struct TcpConnection {
     pub reader: TcpReadBridge,
     pub writer: TcpWriteBridge,
+    permit: Option<ConnectionPermit>,
+    remote_addr: Option<SocketAddr>,
+    forwarded: Option<ForwardedPlayer>,
+    gauge: Arc<ConnectionGauge>,
 }
 
 const BUF_CAP: usize = 8192;
+const DISCONNECT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
 
 impl TcpConnection {
     pub async fn connect_to_server<A: ToSocketAddrs>(target: A) -> io::Result<Self> {
@@ -22,6 +42,15 @@ impl TcpConnection {
         Ok(Self::from_server_connection(conn))
     }
 
+    /// Like `connect_to_server`, but sends `header` (in the binary PROXY protocol v2 form) before
+    /// anything else, for upstreams which expect to sit behind a load balancer.
+    pub async fn connect_to_server_with_proxy_header<A: ToSocketAddrs>(target: A, header: &ProxyHeader) -> io::Result<Self> {
+        let mut conn = TcpStream::connect(target).await?;
+        conn.set_nodelay(true)?;
+        conn.write_all(&header.encode_v2()).await?;
+        Ok(Self::from_server_connection(conn))
+    }
+
     pub fn from_server_connection(server: TcpStream) -> Self {
         Self::from_connection(server, PacketDirection::ClientBound)
     }
@@ -31,14 +60,70 @@ impl TcpConnection {
     }
 
     pub fn from_connection(conn: TcpStream, read_direction: PacketDirection) -> Self {
+        let remote_addr = conn.peer_addr().ok();
         let (reader, writer) = conn.into_split();
         let reader = io::BufReader::with_capacity(BUF_CAP, reader);
+        let gauge = Arc::new(ConnectionGauge::new(&read_direction));
         Self {
-            reader: TcpReadBridge::initial(read_direction, reader),
-            writer: TcpWriteBridge::initial(read_direction.opposite(), writer),
+            reader: TcpReadBridge::initial(read_direction, reader).with_gauge(gauge.clone()),
+            writer: TcpWriteBridge::initial(read_direction.opposite(), writer).with_gauge(gauge.clone()),
+            permit: None,
+            remote_addr,
+            forwarded: None,
+            gauge,
         }
     }
 
+    /// The address of the peer. For connections accepted behind a proxy this is the real client
+    /// address once it is known (from a PROXY protocol header or IP forwarding), rather than the
+    /// proxy's address.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    pub fn set_remote_addr(&mut self, addr: SocketAddr) {
+        self.remote_addr = Some(addr);
+    }
+
+    /// The player details BungeeCord forwarded in the Handshake, for connections accepted by a
+    /// `Listener` with BungeeCord forwarding enabled.
+    pub fn forwarded(&self) -> Option<&ForwardedPlayer> {
+        self.forwarded.as_ref()
+    }
+
+    pub(crate) fn set_forwarded(&mut self, player: ForwardedPlayer) {
+        self.forwarded = Some(player);
+    }
+
+    pub(crate) fn with_permit(mut self, permit: ConnectionPermit) -> Self {
+        self.permit = Some(permit);
+        self
+    }
+
+    /// The permit this connection holds against the `Listener`'s connection limits, if any.
+    pub fn permit(&self) -> Option<&ConnectionPermit> {
+        self.permit.as_ref()
+    }
+
+    /// Takes the connection limit permit, so it can outlive `into_split` or `into_inner` (which
+    /// would otherwise release it).
+    pub fn take_permit(&mut self) -> Option<ConnectionPermit> {
+        self.permit.take()
+    }
+
+    pub fn state(&self) -> &State {
+        self.writer.state()
+    }
+
+    /// Copies the traffic counters of both bridges, as (received, sent).
+    pub fn traffic(&self) -> (TrafficSnapshot, TrafficSnapshot) {
+        (self.reader.stats().snapshot(), self.writer.stats().snapshot())
+    }
+
+    pub fn set_read_timeouts(&mut self, timeouts: ReadTimeouts) {
+        self.reader.set_timeouts(timeouts);
+    }
+
     pub fn split(&mut self) -> (&mut TcpReadBridge, &mut TcpWriteBridge) {
         (&mut self.reader, &mut self.writer)
     }
@@ -47,6 +132,12 @@ impl TcpConnection {
         (self.reader, self.writer)
     }
 
+    /// Keeps this connection counted in the metrics while it is held, for callers which take
+    /// the sockets out with `into_inner`.
+    pub(crate) fn gauge(&self) -> Arc<ConnectionGauge> {
+        self.gauge.clone()
+    }
+
     pub fn into_inner(self) -> (io::BufReader<OwnedReadHalf>, OwnedWriteHalf) {
         (self.reader.into_inner(), self.writer.into_inner())
     }
@@ -62,6 +153,26 @@ impl TcpConnection {
     pub async fn write_raw_packet<'a, P>(&mut self, packet: P) -> anyhow::Result<()> where P: RawPacket<'a> {
         self.writer.write_raw_packet(packet).await
     }
+
+    /// Sends LoginDisconnect or PlayDisconnect (whichever the current state calls for), then shuts
+    /// down the write half. Whatever the peer still sends is read and discarded until it closes
+    /// the connection (or a short timeout passes), because closing a socket with unread data
+    /// makes the peer see a reset instead of the disconnect message.
+    pub async fn disconnect(&mut self, reason: Chat) -> anyhow::Result<()> {
+        match self.state() {
+            State::Login => self.write_packet(Packet578::LoginDisconnect(LoginDisconnectSpec {
+                message: reason,
+            })).await?,
+            State::Play => self.write_packet(Packet578::PlayDisconnect(PlayDisconnectSpec {
+                reason,
+            })).await?,
+            _ => {}
+        }
+
+        self.writer.shutdown().await?;
+        let _ = time::timeout(DISCONNECT_DRAIN_TIMEOUT, self.reader.drain()).await;
+        Ok(())
+    }
 }
 
 impl Bridge for TcpConnection {
@@ -0,0 +1,67 @@
+use super::{Bridge, TcpConnection};
+use mcproto_rs::{
+    protocol::State,
+    v1_15_2::{Packet578, HandshakeSpec, HandshakeNextState, LoginStartSpec},
+};
+use anyhow::Result;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+/// Bytes copied in each direction by a splice.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpliceStats {
+    pub client_to_upstream: u64,
+    pub upstream_to_client: u64,
+}
+
+/// Connects to `target`, replays the Handshake (and the LoginStart, if `username` is set) that
+/// were read from `client`, and then copies bytes between the two sockets until both sides have
+/// closed. No packets are parsed after the replay, so this costs almost nothing per player.
+///
+/// To route on the username, read it first with `login::read_login_start`.
+///
+/// Nothing may have been read from `client` besides the Handshake and LoginStart.
+pub async fn splice<A: ToSocketAddrs>(mut client: TcpConnection, target: A, handshake: HandshakeSpec, username: Option<String>) -> Result<SpliceStats> {
+    let _permit = client.take_permit();
+    let stream = TcpStream::connect(target).await?;
+    stream.set_nodelay(true)?;
+    let mut upstream = TcpConnection::from_server_connection(stream);
+
+    let next_state = match &handshake.next_state {
+        HandshakeNextState::Status => State::Status,
+        HandshakeNextState::Login => State::Login,
+    };
+    upstream.write_packet(Packet578::Handshake(handshake)).await?;
+    upstream.set_state(next_state);
+    if let Some(name) = username {
+        upstream.write_packet(Packet578::LoginStart(LoginStartSpec { name })).await?;
+    }
+
+    let _gauges = (client.gauge(), upstream.gauge());
+    let (client_reader, client_writer) = client.into_inner();
+    let (upstream_reader, upstream_writer) = upstream.into_inner();
+    Ok(copy_bidirectional(client_reader, client_writer, upstream_reader, upstream_writer).await?)
+}
+
+/// Copies `client_reader` to `upstream_writer` and `upstream_reader` to `client_writer` at the
+/// same time, shutting down each writer once its reader reaches EOF.
+pub async fn copy_bidirectional<CR, CW, UR, UW>(client_reader: CR, client_writer: CW, upstream_reader: UR, upstream_writer: UW) -> io::Result<SpliceStats>
+    where CR: AsyncRead + Unpin, CW: AsyncWrite + Unpin, UR: AsyncRead + Unpin, UW: AsyncWrite + Unpin
+{
+    let (client_to_upstream, upstream_to_client) = tokio::try_join!(
+        copy_half(client_reader, upstream_writer),
+        copy_half(upstream_reader, client_writer))?;
+
+    Ok(SpliceStats {
+        client_to_upstream,
+        upstream_to_client,
+    })
+}
+
+async fn copy_half<R, W>(mut reader: R, mut writer: W) -> io::Result<u64> where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let copied = io::copy(&mut reader, &mut writer).await?;
+    writer.shutdown().await?;
+    Ok(copied)
+}
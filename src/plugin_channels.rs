@@ -0,0 +1,185 @@
+use super::WriteBridge;
+use mcproto_rs::{
+    protocol::{HasPacketId, PacketDirection, RawPacket, State},
+    types::RemainingBytes,
+    v1_15_2::{Packet578, RawPacket578, PlayClientPluginMessageSpec, PlayServerPluginMessageSpec},
+    Deserialize,
+    Deserialized,
+};
+use anyhow::{Result, anyhow};
+use tokio::{io::AsyncWrite, sync::mpsc};
+use std::collections::{BTreeSet, HashMap};
+
+pub const REGISTER_CHANNEL: &str = "minecraft:register";
+pub const UNREGISTER_CHANNEL: &str = "minecraft:unregister";
+
+// 1.15.2 plugin message packet ids
+pub const CLIENTBOUND_PLUGIN_MESSAGE_ID: i32 = 0x19;
+pub const SERVERBOUND_PLUGIN_MESSAGE_ID: i32 = 0x0B;
+
+/// A message sent over a plugin channel. Implemented for `Vec<u8>`, for channels which take the
+/// payload as is.
+pub trait PluginMessage: Sized + Send + 'static {
+    fn decode(data: &[u8]) -> Result<Self>;
+
+    fn encode(&self) -> Result<Vec<u8>>;
+}
+
+impl PluginMessage for Vec<u8> {
+    fn decode(data: &[u8]) -> Result<Self> {
+        Ok(data.to_vec())
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        Ok(self.clone())
+    }
+}
+
+type Dispatch = Box<dyn Fn(&[u8]) -> Result<()> + Send + Sync>;
+
+/// Routes Play state plugin messages (CustomPayload) to a receiver per channel, and keeps track of
+/// the channels the peer registered with `minecraft:register` and `minecraft:unregister`.
+///
+/// Works on either side of a connection: feed it the plugin messages read from the peer with
+/// `handle_packet` or `handle_raw`, and send through the `WriteBridge` going to the same peer.
+#[derive(Default)]
+pub struct PluginChannels {
+    channels: HashMap<String, Dispatch>,
+    peer_channels: BTreeSet<String>,
+}
+
+impl PluginChannels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts listening on `channel`, replacing any receiver already registered for it. Messages
+    /// that fail to decode as `T` make `handle_packet` (or `handle_raw`) return the error.
+    pub fn register<T: PluginMessage>(&mut self, channel: impl Into<String>) -> mpsc::UnboundedReceiver<T> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.channels.insert(channel.into(), Box::new(move |data| {
+            // nobody listening anymore is not the peer's fault, so it isn't an error
+            let _ = tx.send(T::decode(data)?);
+            Ok(())
+        }));
+        rx
+    }
+
+    /// Stops listening on `channel`, which ends its receiver. Returns whether it was registered.
+    pub fn unregister(&mut self, channel: &str) -> bool {
+        self.channels.remove(channel).is_some()
+    }
+
+    pub fn is_registered(&self, channel: &str) -> bool {
+        self.channels.contains_key(channel)
+    }
+
+    /// The channels we listen on, in the order they should be announced.
+    pub fn channels(&self) -> Vec<&str> {
+        let mut channels: Vec<&str> = self.channels.keys().map(String::as_str).collect();
+        channels.sort_unstable();
+        channels
+    }
+
+    /// Whether the peer has announced that it listens on `channel`.
+    pub fn peer_registered(&self, channel: &str) -> bool {
+        self.peer_channels.contains(channel)
+    }
+
+    pub fn peer_channels(&self) -> impl Iterator<Item = &str> {
+        self.peer_channels.iter().map(String::as_str)
+    }
+
+    /// Tells the peer which channels we listen on, with a `minecraft:register` message.
+    pub async fn announce<W: AsyncWrite + Unpin>(&self, writer: &mut WriteBridge<W>) -> Result<()> {
+        let channels = self.channels();
+        if channels.is_empty() {
+            return Ok(());
+        }
+
+        send_raw(writer, REGISTER_CHANNEL, channels.join("\0").into_bytes()).await
+    }
+
+    /// Tells the peer we no longer listen on `channels`, with a `minecraft:unregister` message.
+    pub async fn announce_unregister<W: AsyncWrite + Unpin>(&self, writer: &mut WriteBridge<W>, channels: &[&str]) -> Result<()> {
+        send_raw(writer, UNREGISTER_CHANNEL, channels.join("\0").into_bytes()).await
+    }
+
+    /// Sends `message` on `channel`, whether or not the peer registered it.
+    pub async fn send<W: AsyncWrite + Unpin, T: PluginMessage>(&self, writer: &mut WriteBridge<W>, channel: &str, message: &T) -> Result<()> {
+        send_raw(writer, channel, message.encode()?).await
+    }
+
+    /// Delivers one plugin message from the peer. Returns `false` if nothing listens on the
+    /// channel, so the caller can pass it on (or drop it). Register and unregister messages are
+    /// always consumed, after updating the peer's channels.
+    pub fn dispatch(&mut self, channel: &str, data: &[u8]) -> Result<bool> {
+        match channel {
+            REGISTER_CHANNEL => self.peer_channels.extend(channel_names(data)),
+            UNREGISTER_CHANNEL => for name in channel_names(data) {
+                self.peer_channels.remove(&name);
+            },
+            _ => match self.channels.get(channel) {
+                Some(dispatch) => dispatch(data)?,
+                None => return Ok(false),
+            },
+        }
+        Ok(true)
+    }
+
+    /// Like `dispatch`, for a deserialized packet. Packets other than plugin messages return
+    /// `false`.
+    pub fn handle_packet(&mut self, packet: &Packet578) -> Result<bool> {
+        match packet {
+            Packet578::PlayClientPluginMessage(body) => self.dispatch(&body.channel, &body.data.data),
+            Packet578::PlayServerPluginMessage(body) => self.dispatch(&body.channel, &body.data.data),
+            _ => Ok(false),
+        }
+    }
+
+    /// Like `dispatch`, for a raw packet (as read by a relay). Packets other than plugin
+    /// messages return `false` without being deserialized.
+    pub fn handle_raw(&mut self, packet: &RawPacket578<'_>) -> Result<bool> {
+        match plugin_message(packet)? {
+            Some((channel, data)) => self.dispatch(&channel, data),
+            None => Ok(false),
+        }
+    }
+}
+
+/// Splits a raw plugin message (in either direction) into its channel and payload, without
+/// deserializing any other packet. Returns `None` for packets which are not plugin messages.
+pub fn plugin_message<'p>(packet: &'p RawPacket578<'_>) -> Result<Option<(String, &'p [u8])>> {
+    let id = packet.id();
+    let is_plugin_message = id.state == State::Play && match id.direction {
+        PacketDirection::ClientBound => id.id == CLIENTBOUND_PLUGIN_MESSAGE_ID,
+        PacketDirection::ServerBound => id.id == SERVERBOUND_PLUGIN_MESSAGE_ID,
+    };
+    if !is_plugin_message {
+        return Ok(None);
+    }
+
+    let Deserialized { value: channel, data } = String::mc_deserialize(packet.data())?;
+    Ok(Some((channel, data)))
+}
+
+/// Builds the plugin message packet for whichever direction `writer` writes in.
+pub async fn send_raw<W: AsyncWrite + Unpin>(writer: &mut WriteBridge<W>, channel: &str, data: Vec<u8>) -> Result<()> {
+    if writer.state() != &State::Play {
+        return Err(anyhow!("plugin messages can only be sent in the play state, not {:?}", writer.state()));
+    }
+
+    let channel = channel.to_owned();
+    let data = RemainingBytes { data };
+    let packet = match writer.direction() {
+        PacketDirection::ClientBound => Packet578::PlayServerPluginMessage(PlayServerPluginMessageSpec { channel, data }),
+        PacketDirection::ServerBound => Packet578::PlayClientPluginMessage(PlayClientPluginMessageSpec { channel, data }),
+    };
+    writer.write_packet(packet).await
+}
+
+fn channel_names(data: &[u8]) -> impl Iterator<Item = String> + '_ {
+    data.split(|b| *b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+}
@@ -0,0 +1,297 @@
+use super::{
+    TcpConnection,
+    TcpReadBridge,
+    TcpWriteBridge,
+    entity_map::EntityMap,
+    limits::ConnectionPermit,
+    login::LoginOptions,
+    util::read_deserialized,
+};
+use mcproto_rs::{
+    protocol::{HasPacketId, RawPacket, State},
+    v1_15_2::{Packet578, RawPacket578, Dimension, PlayJoinGameSpec, PlayRespawnSpec},
+};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use tokio::{net::ToSocketAddrs, sync::{mpsc, oneshot, Mutex}};
+use std::sync::{Arc, RwLock};
+
+/// What the relay should do with a packet after the hook has seen it.
+#[derive(Debug)]
+pub enum Action {
+    Forward,
+    Drop,
+    /// Send these packets to the other side instead of the original (which can itself be
+    /// included, deserialized and modified, to forward it alongside injected packets).
+    Replace(Vec<Packet578>),
+}
+
+/// Called for every packet passing through a `Relay`, in both directions. The direction of the
+/// packet is `packet.id().direction`.
+///
+/// Packets going in the same direction are handled one at a time, in order, while the two
+/// directions run concurrently. Use the `RelayHandle` to send extra packets to either side.
+///
+/// Entity ids have already been remapped (see `EntityMap`) when the hook sees a packet, so they
+/// are the ids the receiving side will see.
+#[async_trait]
+pub trait PacketHook: Send + Sync + 'static {
+    async fn on_packet(&self, packet: &RawPacket578<'_>, relay: &RelayHandle) -> Result<Action>;
+}
+
+/// A hook which forwards everything unchanged.
+pub struct ForwardAll;
+
+#[async_trait]
+impl PacketHook for ForwardAll {
+    async fn on_packet(&self, _packet: &RawPacket578<'_>, _relay: &RelayHandle) -> Result<Action> {
+        Ok(Action::Forward)
+    }
+}
+
+type SharedWriter = Arc<Mutex<TcpWriteBridge>>;
+
+struct UpstreamLogin {
+    username: String,
+    options: LoginOptions,
+}
+
+struct Swap {
+    upstream: TcpConnection,
+    done: oneshot::Sender<()>,
+}
+
+/// Sends packets to either side of a running `Relay`, and moves the client between upstreams.
+/// Cheap to clone.
+#[derive(Clone)]
+pub struct RelayHandle {
+    client: SharedWriter,
+    upstream: SharedWriter,
+    swaps: mpsc::UnboundedSender<Swap>,
+    login: Arc<RwLock<Option<Arc<UpstreamLogin>>>>,
+}
+
+impl RelayHandle {
+    /// Logs in to a new upstream (using the login configured with `Relay::with_upstream_login`)
+    /// and moves the client to it. If the login fails the client stays on the current upstream.
+    pub async fn switch_upstream<A: ToSocketAddrs>(&self, target: A) -> Result<()> {
+        let login = self.login.read().expect("relay login lock poisoned").clone()
+            .ok_or_else(|| anyhow!("relay has no upstream login configured"))?;
+        let (upstream, _) = TcpConnection::login(target, &login.username, login.options.clone()).await?;
+        self.swap_upstream(upstream).await
+    }
+
+    /// Moves the client to an upstream connection which has already logged in (is in the Play
+    /// state). The previous upstream is closed.
+    ///
+    /// Only the upstream side changes: the client-facing connection keeps its compression and
+    /// encryption. The client is sent a Respawn into a different dimension and then a Respawn
+    /// into the new upstream's dimension, which makes it throw away the old world, in place of
+    /// the new upstream's JoinGame.
+    pub async fn swap_upstream(&self, upstream: TcpConnection) -> Result<()> {
+        if upstream.state() != &State::Play {
+            return Err(anyhow!("new upstream must be in the play state, but it is in {:?}", upstream.state()));
+        }
+
+        let (done, done_rx) = oneshot::channel();
+        self.swaps.send(Swap { upstream, done }).map_err(|_| anyhow!("relay is not running"))?;
+        done_rx.await.map_err(|_| anyhow!("relay stopped before the upstream was swapped"))
+    }
+
+    pub async fn send_to_client(&self, packet: Packet578) -> Result<()> {
+        self.client.lock().await.write_packet(packet).await
+    }
+
+    pub async fn send_to_upstream(&self, packet: Packet578) -> Result<()> {
+        self.upstream.lock().await.write_packet(packet).await
+    }
+}
+
+/// Joins a client-facing connection to an upstream (server-facing) connection, both of which
+/// must already be in the Play state.
+///
+/// Each connection keeps its own bridges, so compression and encryption are configured per side
+/// and packets are re-framed as they pass through.
+pub struct Relay<H> {
+    client_reader: TcpReadBridge,
+    upstream_reader: TcpReadBridge,
+    handle: RelayHandle,
+    swaps: mpsc::UnboundedReceiver<Swap>,
+    hook: Arc<H>,
+    entities: Arc<EntityMap>,
+    _permit: Option<ConnectionPermit>,
+}
+
+impl<H> Relay<H> where H: PacketHook {
+    pub fn new(mut client: TcpConnection, upstream: TcpConnection, hook: H) -> Result<Self> {
+        if client.state() != &State::Play || upstream.state() != &State::Play {
+            return Err(anyhow!(
+                "relay needs both connections in the play state, but client is in {:?} and upstream is in {:?}",
+                client.state(),
+                upstream.state()));
+        }
+
+        let permit = client.take_permit();
+        let (client_reader, client_writer) = client.into_split();
+        let (upstream_reader, upstream_writer) = upstream.into_split();
+        let (swaps_tx, swaps) = mpsc::unbounded_channel();
+        Ok(Self {
+            client_reader,
+            upstream_reader,
+            handle: RelayHandle {
+                client: Arc::new(Mutex::new(client_writer)),
+                upstream: Arc::new(Mutex::new(upstream_writer)),
+                swaps: swaps_tx,
+                login: Arc::new(RwLock::new(None)),
+            },
+            swaps,
+            hook: Arc::new(hook),
+            entities: Arc::new(EntityMap::new()),
+            _permit: permit,
+        })
+    }
+
+    /// The username and options used by `RelayHandle::switch_upstream`, including by handles
+    /// taken before this was set.
+    pub fn with_upstream_login(self, username: impl Into<String>, options: LoginOptions) -> Self {
+        *self.handle.login.write().expect("relay login lock poisoned") = Some(Arc::new(UpstreamLogin {
+            username: username.into(),
+            options,
+        }));
+        self
+    }
+
+    /// Sets the entity id the client was given, for relays started after the first JoinGame was
+    /// already sent to the client. Otherwise it is taken from the first JoinGame relayed.
+    pub fn with_client_entity_id(self, id: i32) -> Self {
+        self.entities.set_client_id(id);
+        self
+    }
+
+    /// Entity ids in packets going through the relay are rewritten by this map once the client
+    /// has been moved to an upstream which assigned it a different entity id.
+    pub fn entity_map(&self) -> Arc<EntityMap> {
+        self.entities.clone()
+    }
+
+    pub fn handle(&self) -> RelayHandle {
+        self.handle.clone()
+    }
+
+    /// Relays packets until either side closes its connection or an error occurs, and then shuts
+    /// down both connections.
+    pub async fn run(self) -> Result<()> {
+        let Relay { client_reader, upstream_reader, handle, mut swaps, hook, entities, _permit } = self;
+        let result = tokio::select! {
+            result = relay(client_reader, hook.as_ref(), &handle, &entities) => result,
+            result = relay_clientbound(upstream_reader, hook.as_ref(), &handle, &entities, &mut swaps) => result,
+        };
+
+        let _ = handle.client.lock().await.shutdown().await;
+        let _ = handle.upstream.lock().await.shutdown().await;
+        result
+    }
+}
+
+async fn relay<H: PacketHook>(mut reader: TcpReadBridge, hook: &H, handle: &RelayHandle, entities: &EntityMap) -> Result<()> {
+    loop {
+        match reader.read_packet::<RawPacket578>().await? {
+            Some(packet) => relay_packet(packet, hook, handle, &handle.upstream, entities).await?,
+            None => return Ok(()),
+        }
+    }
+}
+
+async fn relay_clientbound<H: PacketHook>(
+    mut reader: TcpReadBridge,
+    hook: &H,
+    handle: &RelayHandle,
+    entities: &EntityMap,
+    swaps: &mut mpsc::UnboundedReceiver<Swap>,
+) -> Result<()> {
+    let mut seen_join_game = false;
+    loop {
+        let swap = tokio::select! {
+            read = reader.read_packet::<RawPacket578>() => {
+                let packet = match read? {
+                    Some(packet) => packet,
+                    None => return Ok(()),
+                };
+
+                if !seen_join_game {
+                    if let RawPacket578::PlayJoinGame(body) = &packet {
+                        seen_join_game = true;
+                        entities.set_client_id(body.deserialize()?.entity_id);
+                    }
+                }
+
+                relay_packet(packet, hook, handle, &handle.client, entities).await?;
+                continue;
+            }
+            Some(swap) = swaps.recv() => swap,
+        };
+
+        // interrupting the read above is fine, because it was reading from the upstream we are
+        // about to throw away
+        let (mut new_reader, new_writer) = swap.upstream.into_split();
+        // the new upstream's entity id must be mapped before any client packet reaches it, so
+        // its writer is only installed after its JoinGame
+        let join_game = read_join_game(&mut new_reader).await?;
+        entities.set_server_id(join_game.entity_id);
+        respawn_client(&handle.client, &join_game).await?;
+        let mut old_writer = std::mem::replace(&mut *handle.upstream.lock().await, new_writer);
+        let _ = old_writer.shutdown().await;
+        reader = new_reader;
+        let _ = swap.done.send(());
+    }
+}
+
+async fn read_join_game(reader: &mut TcpReadBridge) -> Result<PlayJoinGameSpec> {
+    match read_deserialized(reader).await? {
+        Some(Packet578::PlayJoinGame(body)) => Ok(body),
+        Some(other) => Err(anyhow!("expected JoinGame from the new upstream but got {:?}", other)),
+        None => Err(anyhow!("new upstream closed before sending JoinGame")),
+    }
+}
+
+async fn respawn_client(client: &SharedWriter, join_game: &PlayJoinGameSpec) -> Result<()> {
+    // respawning into the dimension the client is already in would not reset its world
+    let other_dimension = match join_game.dimension {
+        Dimension::Overworld => Dimension::Nether,
+        _ => Dimension::Overworld,
+    };
+    let mut client = client.lock().await;
+    for dimension in &[other_dimension, join_game.dimension] {
+        client.write_packet(Packet578::PlayRespawn(PlayRespawnSpec {
+            dimension: *dimension,
+            hashed_seed: join_game.hashed_seed,
+            gamemode: join_game.gamemode,
+            level_type: join_game.level_type.clone(),
+        })).await?;
+    }
+    Ok(())
+}
+
+async fn relay_packet<H: PacketHook>(packet: RawPacket578<'_>, hook: &H, handle: &RelayHandle, target: &SharedWriter, entities: &EntityMap) -> Result<()> {
+    match entities.rewrite(packet.id(), packet.data())? {
+        Some(rewritten) => {
+            let packet = RawPacket578::create(packet.id(), &rewritten)?;
+            apply_hook(packet, hook, handle, target).await
+        }
+        None => apply_hook(packet, hook, handle, target).await,
+    }
+}
+
+async fn apply_hook<H: PacketHook>(packet: RawPacket578<'_>, hook: &H, handle: &RelayHandle, target: &SharedWriter) -> Result<()> {
+    match hook.on_packet(&packet, handle).await? {
+        Action::Forward => target.lock().await.write_raw_packet(packet).await,
+        Action::Drop => Ok(()),
+        Action::Replace(packets) => {
+            let mut target = target.lock().await;
+            for packet in packets {
+                target.write_packet(packet).await?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use anyhow::{Result, anyhow};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+// the longest possible v1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+const V2_VERSION: u8 = 0x20;
+const V2_COMMAND_LOCAL: u8 = 0x00;
+const V2_COMMAND_PROXY: u8 = 0x01;
+const V2_FAMILY_INET: u8 = 0x10;
+const V2_FAMILY_INET6: u8 = 0x20;
+const V2_ADDRESSES_INET_LEN: usize = 12;
+const V2_ADDRESSES_INET6_LEN: usize = 36;
+
+/// A HAProxy PROXY protocol header, which load balancers send before any of the client's data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// A connection from `source`, which connected to the load balancer at `destination`.
+    Proxied {
+        source: SocketAddr,
+        destination: SocketAddr,
+    },
+    /// A connection made by the load balancer itself (such as a health check), or one whose
+    /// addresses it did not know. The socket's own peer address applies.
+    Local,
+}
+
+impl ProxyHeader {
+    pub fn source(&self) -> Option<SocketAddr> {
+        match self {
+            ProxyHeader::Proxied { source, .. } => Some(*source),
+            ProxyHeader::Local => None,
+        }
+    }
+
+    /// The text (version 1) form of this header.
+    pub fn encode_v1(&self) -> Vec<u8> {
+        match self {
+            ProxyHeader::Proxied { source, destination } => {
+                let (source, destination) = same_family(*source, *destination);
+                let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    family,
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()).into_bytes()
+            }
+            ProxyHeader::Local => b"PROXY UNKNOWN\r\n".to_vec(),
+        }
+    }
+
+    /// The binary (version 2) form of this header.
+    pub fn encode_v2(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(V2_SIGNATURE.len() + 4 + V2_ADDRESSES_INET6_LEN);
+        out.extend_from_slice(&V2_SIGNATURE);
+        match self {
+            ProxyHeader::Proxied { source, destination } => {
+                out.push(V2_VERSION | V2_COMMAND_PROXY);
+                match same_family(*source, *destination) {
+                    (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+                        out.push(V2_FAMILY_INET | 0x01);
+                        out.extend_from_slice(&(V2_ADDRESSES_INET_LEN as u16).to_be_bytes());
+                        out.extend_from_slice(&source.ip().octets());
+                        out.extend_from_slice(&destination.ip().octets());
+                    }
+                    (source, destination) => {
+                        out.push(V2_FAMILY_INET6 | 0x01);
+                        out.extend_from_slice(&(V2_ADDRESSES_INET6_LEN as u16).to_be_bytes());
+                        out.extend_from_slice(&ipv6(source.ip()).octets());
+                        out.extend_from_slice(&ipv6(destination.ip()).octets());
+                    }
+                }
+                out.extend_from_slice(&source.port().to_be_bytes());
+                out.extend_from_slice(&destination.port().to_be_bytes());
+            }
+            ProxyHeader::Local => {
+                out.push(V2_VERSION | V2_COMMAND_LOCAL);
+                out.push(0);
+                out.extend_from_slice(&0u16.to_be_bytes());
+            }
+        }
+        out
+    }
+}
+
+/// Reads a version 1 or version 2 PROXY protocol header, consuming exactly the header's bytes.
+/// Fails if the stream does not start with one.
+pub async fn read_proxy_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<ProxyHeader> {
+    // both versions are at least this long
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(reader).await
+    } else if start.starts_with(V1_PREFIX) {
+        read_v1(reader, &start).await
+    } else {
+        Err(anyhow!("connection did not start with a PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(reader: &mut R, start: &[u8]) -> Result<ProxyHeader> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(anyhow!("PROXY protocol v1 header is too long"));
+        }
+
+        line.push(reader.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| anyhow!("PROXY protocol v1 header is not valid text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::Local),
+        ["PROXY", family @ "TCP4", source, destination, source_port, destination_port] |
+        ["PROXY", family @ "TCP6", source, destination, source_port, destination_port] => {
+            let parse_ip = |raw: &str| -> Result<IpAddr> {
+                let ip: IpAddr = raw.parse().map_err(|_| anyhow!("invalid address {:?} in PROXY protocol header", raw))?;
+                if ip.is_ipv4() != (*family == "TCP4") {
+                    return Err(anyhow!("address {} does not match PROXY protocol family {}", ip, family));
+                }
+                Ok(ip)
+            };
+            let parse_port = |raw: &str| -> Result<u16> {
+                raw.parse().map_err(|_| anyhow!("invalid port {:?} in PROXY protocol header", raw))
+            };
+
+            Ok(ProxyHeader::Proxied {
+                source: SocketAddr::new(parse_ip(source)?, parse_port(source_port)?),
+                destination: SocketAddr::new(parse_ip(destination)?, parse_port(destination_port)?),
+            })
+        }
+        _ => Err(anyhow!("malformed PROXY protocol v1 header {:?}", line)),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> Result<ProxyHeader> {
+    let mut fixed = [0u8; 4];
+    reader.read_exact(&mut fixed).await?;
+    let [version_command, family, len_high, len_low] = fixed;
+    if version_command & 0xF0 != V2_VERSION {
+        return Err(anyhow!("unsupported PROXY protocol version {:#x}", version_command >> 4));
+    }
+
+    // the addresses are followed by optional TLVs, which are read and ignored
+    let mut rest = vec![0u8; u16::from_be_bytes([len_high, len_low]) as usize];
+    reader.read_exact(&mut rest).await?;
+
+    match version_command & 0x0F {
+        V2_COMMAND_LOCAL => return Ok(ProxyHeader::Local),
+        V2_COMMAND_PROXY => {}
+        other => return Err(anyhow!("unsupported PROXY protocol command {:#x}", other)),
+    }
+
+    // the transport (low nibble) doesn't matter to us, only the address family does
+    match family & 0xF0 {
+        V2_FAMILY_INET if rest.len() >= V2_ADDRESSES_INET_LEN => {
+            let source = Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3]);
+            let destination = Ipv4Addr::new(rest[4], rest[5], rest[6], rest[7]);
+            Ok(ProxyHeader::Proxied {
+                source: SocketAddr::new(source.into(), u16::from_be_bytes([rest[8], rest[9]])),
+                destination: SocketAddr::new(destination.into(), u16::from_be_bytes([rest[10], rest[11]])),
+            })
+        }
+        V2_FAMILY_INET6 if rest.len() >= V2_ADDRESSES_INET6_LEN => {
+            let mut source = [0u8; 16];
+            let mut destination = [0u8; 16];
+            source.copy_from_slice(&rest[0..16]);
+            destination.copy_from_slice(&rest[16..32]);
+            Ok(ProxyHeader::Proxied {
+                source: SocketAddr::new(Ipv6Addr::from(source).into(), u16::from_be_bytes([rest[32], rest[33]])),
+                destination: SocketAddr::new(Ipv6Addr::from(destination).into(), u16::from_be_bytes([rest[34], rest[35]])),
+            })
+        }
+        V2_FAMILY_INET | V2_FAMILY_INET6 => Err(anyhow!("PROXY protocol v2 header is too short for its addresses")),
+        // unspecified or unix socket addresses
+        _ => Ok(ProxyHeader::Local),
+    }
+}
+
+// both addresses of a header must be in the same family, so mixed pairs become IPv6
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (
+            SocketAddr::new(ipv6(source.ip()).into(), source.port()),
+            SocketAddr::new(ipv6(destination.ip()).into(), destination.port()),
+        )
+    }
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers() -> Vec<ProxyHeader> {
+        vec![
+            ProxyHeader::Proxied {
+                source: "192.0.2.10:51234".parse().unwrap(),
+                destination: "198.51.100.1:25565".parse().unwrap(),
+            },
+            ProxyHeader::Proxied {
+                source: "[2001:db8::10]:51234".parse().unwrap(),
+                destination: "[2001:db8::1]:25565".parse().unwrap(),
+            },
+            ProxyHeader::Local,
+        ]
+    }
+
+    // reads `encoded` followed by client data, checking that only the header was consumed
+    async fn read_then_rest(encoded: Vec<u8>) -> Result<ProxyHeader> {
+        let mut data = encoded;
+        data.extend_from_slice(b"\x10\x00");
+        let mut reader = data.as_slice();
+        let header = read_proxy_header(&mut reader).await?;
+        assert_eq!(reader, b"\x10\x00");
+        Ok(header)
+    }
+
+    #[tokio::test]
+    async fn v1_round_trip() -> Result<()> {
+        for header in headers() {
+            assert_eq!(read_then_rest(header.encode_v1()).await?, header);
+        }
+        assert_eq!(ProxyHeader::Local.encode_v1(), b"PROXY UNKNOWN\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn v2_round_trip() -> Result<()> {
+        for header in headers() {
+            assert_eq!(read_then_rest(header.encode_v2()).await?, header);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mixed_families_are_sent_as_ipv6() -> Result<()> {
+        let header = ProxyHeader::Proxied {
+            source: "192.0.2.10:51234".parse().unwrap(),
+            destination: "[2001:db8::1]:25565".parse().unwrap(),
+        };
+        let expected = ProxyHeader::Proxied {
+            source: "[::ffff:192.0.2.10]:51234".parse().unwrap(),
+            destination: "[2001:db8::1]:25565".parse().unwrap(),
+        };
+        assert_eq!(read_then_rest(header.encode_v1()).await?, expected);
+        assert_eq!(read_then_rest(header.encode_v2()).await?, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn v2_skips_tlvs() -> Result<()> {
+        let header = headers()[0];
+        let mut encoded = header.encode_v2();
+        // a NOOP TLV with two bytes of value, counted in the header length
+        encoded[15] += 5;
+        encoded.extend_from_slice(&[0x04, 0x00, 0x02, 0xAA, 0xBB]);
+        assert_eq!(read_then_rest(encoded).await?, header);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_other_data() {
+        let mut handshake: &[u8] = b"\x10\x00\xc2\x04\x09localhost\x63\xdd\x02";
+        assert!(read_proxy_header(&mut handshake).await.is_err());
+        let mut bad_v1: &[u8] = b"PROXY TCP4 not an address\r\n";
+        assert!(read_proxy_header(&mut bad_v1).await.is_err());
+    }
+}
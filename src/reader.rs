@@ -1,13 +1,54 @@
-use super::{bridge::Bridge, cfb8::MinecraftCipher, util::{get_sized_buf, init_buf}};
+use super::{
+    bridge::Bridge,
+    cfb8::MinecraftCipher,
+    metrics::{self, ConnectionGauge, Traffic},
+    stats::TrafficCounter,
+    util::{get_sized_buf, init_buf, varint_len},
+};
 use mcproto_rs::{
     protocol::{State, PacketDirection, RawPacket, Id},
     types::VarInt,
     Deserialize,
     Deserialized,
 };
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::{io::{self, AsyncBufRead, AsyncRead, AsyncReadExt}, future::poll_fn, time::{self, Instant}};
 use anyhow::{Result, anyhow};
 use flate2::{FlushDecompress, Status};
+use std::{fmt, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+/// Limits on how long a `ReadBridge` waits for the peer. Every limit is off by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadTimeouts {
+    /// How long the rest of a packet may take to arrive once its first byte has.
+    pub frame: Option<Duration>,
+    /// How long to wait for the next packet in the Play state.
+    pub idle: Option<Duration>,
+    /// How long the connection may stay in the Handshaking state in total.
+    pub handshake: Option<Duration>,
+    /// How long the connection may stay in the Login state in total.
+    pub login: Option<Duration>,
+}
+
+/// Returned (inside the `anyhow::Error`) when a read exceeds one of the `ReadTimeouts`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReadTimeoutError {
+    Frame(Duration),
+    Idle(Duration),
+    /// The connection spent too long in the Handshaking or Login state.
+    StateDeadline(State, Duration),
+}
+
+impl fmt::Display for ReadTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadTimeoutError::Frame(after) => write!(f, "packet did not arrive within {:?} of its first byte", after),
+            ReadTimeoutError::Idle(after) => write!(f, "no packet received for {:?}", after),
+            ReadTimeoutError::StateDeadline(state, after) => write!(f, "connection stayed in the {:?} state for longer than {:?}", state, after),
+        }
+    }
+}
+
+impl std::error::Error for ReadTimeoutError {}
 
 pub struct ReadBridge<R> {
     stream: R,
@@ -16,7 +57,11 @@ pub struct ReadBridge<R> {
     compression_threshold: Option<i32>,
     state: State,
     direction: PacketDirection,
-    encryption: Option<MinecraftCipher>
+    encryption: Option<MinecraftCipher>,
+    timeouts: ReadTimeouts,
+    state_entered_at: Instant,
+    stats: Arc<TrafficCounter>,
+    gauge: Option<Arc<ConnectionGauge>>,
 }
 
 impl<R> ReadBridge<R> where R: AsyncRead + Unpin {
@@ -29,15 +74,40 @@ impl<R> ReadBridge<R> where R: AsyncRead + Unpin {
             decompress_buf: None,
             compression_threshold: None,
             encryption: None,
+            timeouts: ReadTimeouts::default(),
+            state_entered_at: Instant::now(),
+            stats: Arc::new(TrafficCounter::new()),
+            gauge: None,
         }
     }
 
+    /// Keeps `gauge` alive and up to date with the state and encryption of this bridge.
+    pub(crate) fn with_gauge(mut self, gauge: Arc<ConnectionGauge>) -> Self {
+        self.gauge = Some(gauge);
+        self
+    }
+
+    /// Counts every packet read by this bridge.
+    pub fn stats(&self) -> &Arc<TrafficCounter> {
+        &self.stats
+    }
+
+    /// Timeouts apply to reads started after this is called. A read which times out leaves the
+    /// stream part way through a packet, so the connection has to be closed afterwards.
+    pub fn set_timeouts(&mut self, timeouts: ReadTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn timeouts(&self) -> &ReadTimeouts {
+        &self.timeouts
+    }
+
     pub async fn read_packet<'a, P: RawPacket<'a>>(&'a mut self) -> Result<Option<P>> {
         // pinning stuff makes this a requirement
         let this = &mut *self;
 
         // read the packet length
-        let packet_len = match this.read_one_varint().await? {
+        let (packet_len, frame_deadline) = match this.read_one_varint().await? {
             Some(v) => v,
             None => return Ok(None)
         };
@@ -49,7 +119,7 @@ impl<R> ReadBridge<R> where R: AsyncRead + Unpin {
         // buf for raw data
         let raw_buf = init_buf(&mut this.raw_buf, 512);
         let mut buf = get_sized_buf(raw_buf, packet_len.0 as usize);
-        reader.read_exact(buf).await?;
+        with_deadline(frame_deadline, reader.read_exact(buf)).await?;
 
         // decrypt if we have encryption state
         if let Some(encryption) = this.encryption.as_mut() {
@@ -65,6 +135,7 @@ impl<R> ReadBridge<R> where R: AsyncRead + Unpin {
             // data_len is 0 when it is not compressed, and non-zero otherwise
             // if it is non-zero, decompress:
             if data_len.0 != 0 {
+                let started_at = std::time::Instant::now();
                 let mut decompress = flate2::Decompress::new(true);
                 let needed = data_len.0 as usize;
                 let decompress_buf = &mut this.decompress_buf;
@@ -83,6 +154,7 @@ impl<R> ReadBridge<R> where R: AsyncRead + Unpin {
                     }
                 }
 
+                metrics::global().record_decompression(started_at.elapsed());
                 &mut decompress_buf[..(decompress.total_out() as usize)]
             } else {
                 buf
@@ -92,7 +164,11 @@ impl<R> ReadBridge<R> where R: AsyncRead + Unpin {
         };
 
         // read packet id from buf
+        let data_len = buf.len();
         let Deserialized { value: packet_id, data: buf } = VarInt::mc_deserialize(buf)?;
+        let wire_len = varint_len(packet_len.0) + packet_len.0 as usize;
+        this.stats.record(&this.state, packet_id.0, wire_len, data_len);
+        metrics::global().record_packet(Traffic::Received, &this.state, packet_id.0, wire_len, data_len);
         Ok(Some(P::create(Id{
             id: packet_id.0,
             state: this.state.clone(),
@@ -100,17 +176,23 @@ impl<R> ReadBridge<R> where R: AsyncRead + Unpin {
         }, buf)?))
     }
 
-    async fn read_one_varint(&mut self) -> Result<Option<VarInt>> {
+    // also returns the deadline for the rest of the frame, which starts with its first byte
+    async fn read_one_varint(&mut self) -> Result<Option<(VarInt, Option<(Instant, ReadTimeoutError)>)>> {
         let mut buf = [0u8; 5];
         let mut len = 0usize;
         let mut has_more = true;
+        let mut deadline = self.first_byte_deadline();
         while has_more {
             if len == 5 {
                 return Err(anyhow!("varint too long while reading id/length/whatever"));
             }
 
             let target = &mut buf[len..len + 1];
-            let size = self.stream.read(target).await?;
+            let size = with_deadline(deadline.clone(), self.stream.read(target)).await?;
+            if len == 0 {
+                deadline = self.frame_deadline();
+            }
+
             if size == 0 {
                 return Ok(None);
             }
@@ -123,17 +205,90 @@ impl<R> ReadBridge<R> where R: AsyncRead + Unpin {
             len += 1;
         }
 
-        Ok(Some(VarInt::mc_deserialize(&buf[..len])?.value))
+        Ok(Some((VarInt::mc_deserialize(&buf[..len])?.value, deadline)))
+    }
+
+    // the state deadline (Handshaking and Login) or idle timeout (Play), whichever is first
+    fn first_byte_deadline(&self) -> Option<(Instant, ReadTimeoutError)> {
+        let idle = match (&self.state, self.timeouts.idle) {
+            (State::Play, Some(idle)) => Some((Instant::now() + idle, ReadTimeoutError::Idle(idle))),
+            _ => None,
+        };
+        earliest(self.state_deadline(), idle)
+    }
+
+    fn frame_deadline(&self) -> Option<(Instant, ReadTimeoutError)> {
+        let frame = self.timeouts.frame.map(|frame| (Instant::now() + frame, ReadTimeoutError::Frame(frame)));
+        earliest(self.state_deadline(), frame)
+    }
+
+    fn state_deadline(&self) -> Option<(Instant, ReadTimeoutError)> {
+        let limit = match self.state {
+            State::Handshaking => self.timeouts.handshake,
+            State::Login => self.timeouts.login,
+            _ => None,
+        }?;
+        Some((self.state_entered_at + limit, ReadTimeoutError::StateDeadline(self.state.clone(), limit)))
+    }
+
+    /// Reads and discards everything until the peer closes its side of the stream.
+    pub async fn drain(&mut self) -> Result<()> {
+        let mut buf = [0u8; 512];
+        while self.stream.read(&mut buf).await? != 0 {}
+        Ok(())
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
     }
 
     pub fn into_inner(self) -> R {
         self.stream
     }
+
+    /// The underlying stream, for reading data which precedes the Minecraft protocol (such as a
+    /// PROXY protocol header). Must only be used before any packet has been read.
+    pub(crate) fn stream_mut(&mut self) -> &mut R {
+        &mut self.stream
+    }
+}
+
+impl<R> ReadBridge<R> where R: AsyncBufRead + Unpin {
+    /// Returns the next byte on the wire without consuming it. Encryption is not applied, so this
+    /// is only meaningful before encryption is enabled (for example to detect a legacy ping).
+    pub async fn peek_byte(&mut self) -> Result<Option<u8>> {
+        let stream = &mut self.stream;
+        let next = poll_fn(|cx| Pin::new(&mut *stream)
+            .poll_fill_buf(cx)
+            .map_ok(|buf| buf.first().cloned())).await?;
+        Ok(next)
+    }
+}
+
+fn earliest(a: Option<(Instant, ReadTimeoutError)>, b: Option<(Instant, ReadTimeoutError)>) -> Option<(Instant, ReadTimeoutError)> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b.0 < a.0 { b } else { a }),
+        (a, b) => a.or(b),
+    }
+}
+
+async fn with_deadline<T, F>(deadline: Option<(Instant, ReadTimeoutError)>, read: F) -> Result<T> where F: Future<Output = io::Result<T>> {
+    match deadline {
+        Some((at, err)) => match time::timeout_at(at, read).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(err.into()),
+        },
+        None => Ok(read.await?),
+    }
 }
 
 impl<R> Bridge for ReadBridge<R> {
     fn set_state(&mut self, next: State) {
+        if let Some(gauge) = &self.gauge {
+            gauge.set_state(&next);
+        }
         self.state = next;
+        self.state_entered_at = Instant::now();
     }
 
     fn set_compression_threshold(&mut self, threshold: Option<i32>) {
@@ -146,6 +301,9 @@ impl<R> Bridge for ReadBridge<R> {
         }
 
         self.encryption = Some(MinecraftCipher::new(key, iv)?);
+        if let Some(gauge) = &self.gauge {
+            gauge.set_encrypted();
+        }
         Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,150 @@
+use mcproto_rs::protocol::State;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const STATE_NAMES: [&str; 4] = ["handshaking", "status", "login", "play"];
+// the per-packet counters of each state start at its offset, with room for every 1.15.2 packet
+// id of that state (1 handshaking, 2 status, 5 login, under 0x80 play)
+const STATE_OFFSETS: [usize; 5] = [0, 0x01, 0x03, 0x08, 0x88];
+
+/// Counts for the packets of one kind, or for all packets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PacketCounts {
+    pub packets: u64,
+    /// Bytes on the wire: length prefix, then the (possibly compressed) packet.
+    pub wire_bytes: u64,
+    /// Bytes of packet id and body before compression.
+    pub data_bytes: u64,
+}
+
+impl PacketCounts {
+    /// Wire bytes per uncompressed byte, so lower is better. `None` before any packet.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.data_bytes == 0 {
+            None
+        } else {
+            Some(self.wire_bytes as f64 / self.data_bytes as f64)
+        }
+    }
+}
+
+/// Identifies a kind of packet in one direction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PacketKey {
+    pub state: &'static str,
+    pub id: i32,
+}
+
+/// A point in time copy of a `TrafficCounter`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrafficSnapshot {
+    pub total: PacketCounts,
+    pub by_packet: HashMap<PacketKey, PacketCounts>,
+}
+
+impl TrafficSnapshot {
+    /// Packet kinds ordered from the most wire bytes to the least.
+    pub fn heaviest(&self) -> Vec<(PacketKey, PacketCounts)> {
+        let mut packets: Vec<_> = self.by_packet.iter().map(|(key, counts)| (*key, *counts)).collect();
+        packets.sort_by(|(_, a), (_, b)| b.wire_bytes.cmp(&a.wire_bytes));
+        packets
+    }
+}
+
+/// Traffic counters for one direction of a connection, updated by its `ReadBridge` or
+/// `WriteBridge`. Shared through an `Arc`, so it can be read from other tasks while the bridge is
+/// in use.
+///
+/// Every count is an atomic, so recording a packet never blocks. Packets with ids outside the
+/// 1.15.2 range only count towards the totals.
+pub struct TrafficCounter {
+    total: AtomicCounts,
+    // indexed with STATE_OFFSETS
+    by_packet: Box<[AtomicCounts]>,
+}
+
+#[derive(Default)]
+struct AtomicCounts {
+    packets: AtomicU64,
+    wire_bytes: AtomicU64,
+    data_bytes: AtomicU64,
+}
+
+impl AtomicCounts {
+    fn add(&self, wire_bytes: usize, data_bytes: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.wire_bytes.fetch_add(wire_bytes as u64, Ordering::Relaxed);
+        self.data_bytes.fetch_add(data_bytes as u64, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> PacketCounts {
+        PacketCounts {
+            packets: self.packets.load(Ordering::Relaxed),
+            wire_bytes: self.wire_bytes.load(Ordering::Relaxed),
+            data_bytes: self.data_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for TrafficCounter {
+    fn default() -> Self {
+        Self {
+            total: AtomicCounts::default(),
+            by_packet: (0..STATE_OFFSETS[4]).map(|_| AtomicCounts::default()).collect(),
+        }
+    }
+}
+
+impl TrafficCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn total(&self) -> PacketCounts {
+        self.total.load()
+    }
+
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        let mut by_packet = HashMap::new();
+        for (index, state) in STATE_NAMES.iter().enumerate() {
+            let counters = &self.by_packet[STATE_OFFSETS[index]..STATE_OFFSETS[index + 1]];
+            for (id, counts) in counters.iter().enumerate() {
+                let counts = counts.load();
+                if counts.packets > 0 {
+                    by_packet.insert(PacketKey { state: *state, id: id as i32 }, counts);
+                }
+            }
+        }
+
+        TrafficSnapshot {
+            total: self.total(),
+            by_packet,
+        }
+    }
+
+    pub(crate) fn record(&self, state: &State, id: i32, wire_bytes: usize, data_bytes: usize) {
+        self.total.add(wire_bytes, data_bytes);
+        let index = state_index(state);
+        let (start, end) = (STATE_OFFSETS[index], STATE_OFFSETS[index + 1]);
+        // negative ids wrap around to past the end, like ids that are too large
+        let id = id as usize;
+        if id < end - start {
+            self.by_packet[start + id].add(wire_bytes, data_bytes);
+        }
+    }
+}
+
+pub fn state_name(state: &State) -> &'static str {
+    STATE_NAMES[state_index(state)]
+}
+
+pub(crate) fn state_index(state: &State) -> usize {
+    match state {
+        State::Handshaking => 0,
+        State::Status => 1,
+        State::Login => 2,
+        State::Play => 3,
+    }
+}
@@ -0,0 +1,157 @@
+use super::{Bridge, TcpConnection, util::read_deserialized};
+use mcproto_rs::{
+    protocol::State,
+    status::StatusSpec,
+    types::VarInt,
+    v1_15_2::{
+        Packet578,
+        HandshakeSpec,
+        HandshakeNextState,
+        StatusRequestSpec,
+        StatusPingSpec,
+        StatusResponseSpec,
+        StatusPongSpec,
+    },
+};
+use anyhow::{Result, anyhow};
+use tokio::{io::AsyncWriteExt, net::{TcpStream, ToSocketAddrs}, time};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug)]
+pub struct PingResult {
+    pub status: StatusSpec,
+    pub latency: Duration,
+}
+
+/// Performs a server list ping, the same way the client's multiplayer menu does. The whole
+/// exchange (connecting included) has to finish within `timeout`.
+pub async fn ping<A: ToSocketAddrs>(target: A, protocol_version: i32, timeout: Duration) -> Result<PingResult> {
+    match time::timeout(timeout, ping_inner(target, protocol_version)).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("status ping timed out after {:?}", timeout)),
+    }
+}
+
+async fn ping_inner<A: ToSocketAddrs>(target: A, protocol_version: i32) -> Result<PingResult> {
+    let stream = TcpStream::connect(target).await?;
+    stream.set_nodelay(true)?;
+    let peer = stream.peer_addr()?;
+    let mut conn = TcpConnection::from_server_connection(stream);
+
+    conn.write_packet(Packet578::Handshake(HandshakeSpec {
+        version: VarInt(protocol_version),
+        server_address: peer.ip().to_string(),
+        server_port: peer.port(),
+        next_state: HandshakeNextState::Status,
+    })).await?;
+    conn.set_state(State::Status);
+
+    conn.write_packet(Packet578::StatusRequest(StatusRequestSpec {})).await?;
+    let status = match read_deserialized(&mut conn.reader).await? {
+        Some(Packet578::StatusResponse(body)) => body.response,
+        Some(other) => return Err(anyhow!("expected StatusResponse but got {:?}", other)),
+        None => return Err(anyhow!("connection closed before StatusResponse")),
+    };
+
+    let payload = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+    let sent_at = Instant::now();
+    conn.write_packet(Packet578::StatusPing(StatusPingSpec { payload })).await?;
+    match read_deserialized(&mut conn.reader).await? {
+        Some(Packet578::StatusPong(body)) if body.payload == payload => {}
+        Some(Packet578::StatusPong(body)) => return Err(anyhow!("pong payload {} does not match ping payload {}", body.payload, payload)),
+        Some(other) => return Err(anyhow!("expected StatusPong but got {:?}", other)),
+        None => return Err(anyhow!("connection closed before StatusPong")),
+    }
+
+    Ok(PingResult {
+        status,
+        latency: sent_at.elapsed(),
+    })
+}
+
+/// Supplies the response for StatusRequest packets. Implemented for `StatusSpec` itself (a fixed
+/// response) and for closures, so the response can be computed per request.
+pub trait StatusProvider: Send + Sync {
+    fn status(&self) -> StatusSpec;
+}
+
+impl StatusProvider for StatusSpec {
+    fn status(&self) -> StatusSpec {
+        self.clone()
+    }
+}
+
+impl<F> StatusProvider for F where F: Fn() -> StatusSpec + Send + Sync {
+    fn status(&self) -> StatusSpec {
+        (self)()
+    }
+}
+
+pub const DEFAULT_STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves the server side of the Status state on a connection whose Handshake asked for Status.
+pub struct StatusResponder<P> {
+    provider: P,
+    timeout: Duration,
+}
+
+impl<P> StatusResponder<P> where P: StatusProvider {
+    pub fn new(provider: P) -> Self {
+        Self {
+            provider,
+            timeout: DEFAULT_STATUS_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn provider(&self) -> &P {
+        &self.provider
+    }
+
+    /// Answers StatusRequest and Ping until the client hangs up, answers a Ping (which is always
+    /// the last thing a client sends) or the timeout elapses. The connection is closed afterwards.
+    pub async fn respond(&self, mut conn: TcpConnection) -> Result<()> {
+        conn.set_state(State::Status);
+        let result = match time::timeout(self.timeout, self.respond_inner(&mut conn)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("status connection timed out after {:?}", self.timeout)),
+        };
+
+        let (_, mut writer) = conn.into_inner();
+        let _ = writer.shutdown().await;
+        result
+    }
+
+    async fn respond_inner(&self, conn: &mut TcpConnection) -> Result<()> {
+        let mut sent_response = false;
+        loop {
+            match read_deserialized(&mut conn.reader).await? {
+                Some(Packet578::StatusRequest(_)) => {
+                    if sent_response {
+                        return Err(anyhow!("client sent StatusRequest more than once"));
+                    }
+
+                    sent_response = true;
+                    conn.write_packet(Packet578::StatusResponse(StatusResponseSpec {
+                        response: self.provider.status(),
+                    })).await?;
+                }
+                Some(Packet578::StatusPing(body)) => {
+                    conn.write_packet(Packet578::StatusPong(StatusPongSpec {
+                        payload: body.payload,
+                    })).await?;
+                    return Ok(());
+                }
+                Some(other) => return Err(anyhow!("unexpected packet {:?} in status state", other)),
+                None => return Ok(()),
+            }
+        }
+    }
+}
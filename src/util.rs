@@ -1,3 +1,17 @@
+use super::ReadBridge;
+use mcproto_rs::{
+    protocol::RawPacket,
+    types::VarInt,
+    v1_15_2::{Packet578, RawPacket578},
+    Deserialize,
+    Deserialized,
+    Serialize,
+    SerializeResult,
+    Serializer,
+};
+use tokio::io::AsyncRead;
+use anyhow::{Result, anyhow};
+
 pub fn get_sized_buf(raw_buf: &mut Vec<u8>, needed: usize) -> &mut [u8] {
     let cur_len = raw_buf.len();
     if cur_len < needed {
@@ -23,4 +37,132 @@ pub fn init_buf(buf: &mut Option<Vec<u8>>, default_cap: usize) -> &mut Vec<u8> {
         *buf = Some(out);
         buf.as_mut().expect("just set")
     }
-}
\ No newline at end of file
+}
+
+pub async fn read_deserialized<R>(reader: &mut ReadBridge<R>) -> Result<Option<Packet578>> where R: AsyncRead + Unpin {
+    Ok(match reader.read_packet::<RawPacket578>().await? {
+        Some(raw) => Some(raw.deserialize()?),
+        None => None,
+    })
+}
+
+pub fn varint_len(value: i32) -> usize {
+    let mut value = value as u32;
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/// Reads from the front of a byte slice, for payloads mcproto-rs has no spec for (plugin
+/// messages, forwarding data, packet bodies being rewritten). Protocol types go through their
+/// `Deserialize` impls; the `java_*` methods read the u16-prefixed types of Java's `DataInput`.
+pub struct ByteReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn remaining(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn read<T: Deserialize>(&mut self) -> Result<T> {
+        let Deserialized { value, data } = T::mc_deserialize(self.data)?;
+        self.data = data;
+        Ok(value)
+    }
+
+    pub fn varint(&mut self) -> Result<i32> {
+        Ok(self.read::<VarInt>()?.0)
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(anyhow!("needed {} more bytes but only {} are left", n, self.data.len()));
+        }
+
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    // writeUTF is modified UTF-8, which only differs from UTF-8 for nulls and characters outside
+    // the BMP, neither of which show up in the messages we read
+    pub fn java_utf(&mut self) -> Result<String> {
+        let len = self.read::<u16>()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    pub fn java_bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.read::<u16>()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+/// The writing counterpart of `ByteReader`.
+#[derive(Default)]
+pub struct ByteWriter {
+    data: Vec<u8>,
+}
+
+impl ByteWriter {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            data: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn write<T: Serialize>(&mut self, value: &T) -> &mut Self {
+        value.mc_serialize(self).expect("serializing to a vec cannot fail");
+        self
+    }
+
+    pub fn varint(&mut self, value: i32) -> &mut Self {
+        self.write(&VarInt(value))
+    }
+
+    /// A protocol string, without copying it into a `String` first.
+    pub fn string(&mut self, value: &str) -> &mut Self {
+        self.varint(value.len() as i32).bytes(value.as_bytes())
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.data.extend_from_slice(value);
+        self
+    }
+
+    pub fn java_utf(&mut self, value: &str) -> Result<&mut Self> {
+        self.java_bytes(value.as_bytes())
+    }
+
+    /// Fails for values over 65535 bytes, which don't fit the u16 length (`writeUTF` throws for
+    /// these too).
+    pub fn java_bytes(&mut self, value: &[u8]) -> Result<&mut Self> {
+        if value.len() > u16::MAX as usize {
+            return Err(anyhow!("{} bytes is too long for a u16 length prefix", value.len()));
+        }
+
+        Ok(self.write(&(value.len() as u16)).bytes(value))
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Serializer for ByteWriter {
+    fn serialize_bytes(&mut self, data: &[u8]) -> SerializeResult {
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn serialize_byte(&mut self, byte: u8) -> SerializeResult {
+        self.serialize_bytes(&[byte])
+    }
+}
@@ -0,0 +1,168 @@
+use super::{TcpConnection, status::StatusResponder};
+use mcproto_rs::{
+    protocol::State,
+    status::StatusSpec,
+    types::Chat,
+    v1_15_2::HandshakeSpec,
+};
+use anyhow::Result;
+use std::collections::HashMap;
+
+const DEFAULT_UNKNOWN_HOST_MESSAGE: &str = "Unknown host, please connect using a valid address";
+
+/// Picks an upstream address based on the address a client typed in, which it sends as the
+/// Handshake's `server_address`.
+///
+/// Routes are either exact (`play.example.com`) or wildcards (`*.example.com`, which matches any
+/// subdomain but not `example.com` itself). Exact routes win over wildcards, and longer wildcards
+/// win over shorter ones. Hosts matching no route use the default route, if one is set.
+#[derive(Clone)]
+pub struct Router {
+    exact: HashMap<String, String>,
+    // (suffix including the leading dot, upstream), kept sorted from longest suffix to shortest
+    wildcards: Vec<(String, String)>,
+    default: Option<String>,
+    unknown_host_message: Chat,
+    unknown_host_status: Option<StatusSpec>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self {
+            exact: HashMap::new(),
+            wildcards: Vec::new(),
+            default: None,
+            unknown_host_message: Chat::from_text(DEFAULT_UNKNOWN_HOST_MESSAGE),
+            unknown_host_status: None,
+        }
+    }
+
+    pub fn route(mut self, host: &str, upstream: impl Into<String>) -> Self {
+        let host = normalize_host(host);
+        let upstream = upstream.into();
+        if host.starts_with("*.") {
+            let suffix = host[1..].to_owned();
+            self.wildcards.retain(|(existing, _)| existing != &suffix);
+            self.wildcards.push((suffix, upstream));
+            self.wildcards.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+        } else {
+            self.exact.insert(host, upstream);
+        }
+        self
+    }
+
+    pub fn default_route(mut self, upstream: impl Into<String>) -> Self {
+        self.default = Some(upstream.into());
+        self
+    }
+
+    /// Sent as the Disconnect to clients logging in through an unknown host.
+    pub fn unknown_host_message(mut self, message: Chat) -> Self {
+        self.unknown_host_message = message;
+        self
+    }
+
+    /// Sent as the status to clients pinging through an unknown host. Without one those
+    /// connections are just closed.
+    pub fn unknown_host_status(mut self, status: StatusSpec) -> Self {
+        self.unknown_host_status = Some(status);
+        self
+    }
+
+    pub fn resolve(&self, server_address: &str) -> Option<&str> {
+        let host = normalize_host(server_address);
+        if let Some(upstream) = self.exact.get(&host) {
+            return Some(upstream);
+        }
+
+        self.wildcards.iter()
+            .find(|(suffix, _)| host.ends_with(suffix.as_str()) && host.len() > suffix.len())
+            .map(|(_, upstream)| upstream.as_str())
+            .or_else(|| self.default.as_deref())
+    }
+
+    pub fn resolve_handshake(&self, handshake: &HandshakeSpec) -> Option<&str> {
+        self.resolve(&handshake.server_address)
+    }
+
+    /// Answers a connection whose host did not resolve to an upstream, with the unknown host
+    /// Disconnect (Login) or status (Status), and closes it.
+    pub async fn reject(&self, mut conn: TcpConnection) -> Result<()> {
+        match conn.state() {
+            State::Status => match &self.unknown_host_status {
+                Some(status) => StatusResponder::new(status.clone()).respond(conn).await,
+                None => conn.disconnect(self.unknown_host_message.clone()).await,
+            },
+            _ => conn.disconnect(self.unknown_host_message.clone()).await,
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Strips what clients and mods add to the address typed in: Forge appends `\0FML\0` (and
+/// BungeeCord forwarding appends more null separated data), and a fully qualified name may end
+/// with a dot. Hostnames are case insensitive, so the result is lowercase.
+pub fn normalize_host(server_address: &str) -> String {
+    let host = match server_address.find('\0') {
+        Some(at) => &server_address[..at],
+        None => server_address,
+    };
+
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router {
+        Router::new()
+            .route("play.example.com", "exact:25565")
+            .route("*.example.com", "wildcard:25565")
+            .route("*.eu.example.com", "eu:25565")
+    }
+
+    #[test]
+    fn exact_routes_win_over_wildcards() {
+        let router = router();
+        assert_eq!(router.resolve("play.example.com"), Some("exact:25565"));
+        assert_eq!(router.resolve("lobby.example.com"), Some("wildcard:25565"));
+    }
+
+    #[test]
+    fn longest_wildcard_wins() {
+        let router = router();
+        assert_eq!(router.resolve("mc.eu.example.com"), Some("eu:25565"));
+        assert_eq!(router.resolve("eu.example.com"), Some("wildcard:25565"));
+
+        // regardless of the order routes were added in
+        let router = Router::new()
+            .route("*.eu.example.com", "eu:25565")
+            .route("*.example.com", "wildcard:25565");
+        assert_eq!(router.resolve("mc.eu.example.com"), Some("eu:25565"));
+    }
+
+    #[test]
+    fn wildcards_do_not_match_their_own_domain() {
+        let router = router();
+        assert_eq!(router.resolve("example.com"), None);
+        assert_eq!(router.resolve("notexample.com"), None);
+        assert_eq!(router.clone().default_route("default:25565").resolve("example.com"), Some("default:25565"));
+    }
+
+    #[test]
+    fn hosts_are_normalized() {
+        assert_eq!(normalize_host("Play.Example.COM"), "play.example.com");
+        assert_eq!(normalize_host("play.example.com.\0FML\0"), "play.example.com");
+        assert_eq!(normalize_host("play.example.com\0203.0.113.5\0069a79f444e94726a5befca90e38aaf5"), "play.example.com");
+
+        let router = router();
+        assert_eq!(router.resolve("PLAY.example.com.\0FML\0"), Some("exact:25565"));
+        assert_eq!(router.resolve("lobby.example.com."), Some("wildcard:25565"));
+    }
+}
@@ -1,4 +1,4 @@
-use super::{bridge::Bridge, util::{get_sized_buf, init_buf}, cfb8::MinecraftCipher};
+use super::{bridge::Bridge, metrics::{self, ConnectionGauge, Traffic}, stats::TrafficCounter, util::{get_sized_buf, init_buf}, cfb8::MinecraftCipher};
 use mcproto_rs::{
     types::VarInt,
     protocol::{State, PacketDirection, Id, RawPacket, Packet},
@@ -8,7 +8,7 @@ use mcproto_rs::{
 };
 use anyhow::{Result, anyhow};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
-use std::ops::Range;
+use std::{ops::Range, sync::Arc};
 use flate2::{Compression, FlushCompress, Status};
 
 pub struct WriteBridge<W> {
@@ -19,6 +19,9 @@ pub struct WriteBridge<W> {
     state: State,
     direction: PacketDirection,
     encryption: Option<MinecraftCipher>,
+    stats: Arc<TrafficCounter>,
+    // only held, so the connection is counted for as long as either bridge is alive
+    _gauge: Option<Arc<ConnectionGauge>>,
 }
 
 const EXTRA_FREE_SPACE: usize = 15;
@@ -33,9 +36,21 @@ impl<W> WriteBridge<W> where W: AsyncWrite + Unpin {
             compress_buf: None,
             compression_threshold: None,
             encryption: None,
+            stats: Arc::new(TrafficCounter::new()),
+            _gauge: None,
         }
     }
 
+    pub(crate) fn with_gauge(mut self, gauge: Arc<ConnectionGauge>) -> Self {
+        self._gauge = Some(gauge);
+        self
+    }
+
+    /// Counts every packet written by this bridge.
+    pub fn stats(&self) -> &Arc<TrafficCounter> {
+        &self.stats
+    }
+
     pub async fn write_raw_packet<'a, P>(&mut self, packet: P) -> Result<()> where P: RawPacket<'a> {
         let raw_buf = init_buf(&mut self.raw_buf, 512);
         let start_at = EXTRA_FREE_SPACE;
@@ -97,6 +112,7 @@ impl<W> WriteBridge<W> where W: AsyncWrite + Unpin {
                 (raw_buf, data_len_at, packet_end_at)
             } else {
                 let src = &raw_buf[data_start_at..data_start_at + data_len];
+                let started_at = std::time::Instant::now();
 
                 let mut compressor = flate2::Compress::new_with_window_bits(Compression::fast(), true, 15);
                 let compress_buf = &mut this.compress_buf;
@@ -128,6 +144,7 @@ impl<W> WriteBridge<W> where W: AsyncWrite + Unpin {
                         Status::StreamEnd => break
                     }
                 }
+                metrics::global().record_compression(started_at.elapsed());
 
                 // write data_len to raw_buf
                 let data_len_start_at = EXTRA_FREE_SPACE - 5;
@@ -172,9 +189,31 @@ impl<W> WriteBridge<W> where W: AsyncWrite + Unpin {
         }
 
         this.stream.write_all(packet_data).await?;
+        this.stats.record(&this.state, id.id, packet_data.len(), data_len);
+        metrics::global().record_packet(Traffic::Sent, &this.state, id.id, packet_data.len(), data_len);
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        self.stream.flush().await?;
         Ok(())
     }
 
+    /// Flushes and shuts down the write half of the stream. The peer sees this as EOF.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// The direction of the packets this bridge writes.
+    pub fn direction(&self) -> &PacketDirection {
+        &self.direction
+    }
+
     pub fn into_inner(self) -> W {
         self.stream
     }